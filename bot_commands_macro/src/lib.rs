@@ -11,18 +11,21 @@ use syn::{
 };
 
 const CMD_ATTR: &str = "handler";
+const CALLBACK_ATTR: &str = "callback";
 
 struct CommandAttribute {
     command: Option<String>,
     chat_mode_start: Option<bool>,
     chat_mode_exit: Option<bool>,
     llm_request: Option<bool>,
+    allowed_roles: Vec<String>,
 }
 
 #[proc_macro_attribute]
 pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
     let module = parse_macro_input!(input as ItemMod);
     let mut commands = Vec::new();
+    let mut callbacks = Vec::new();
     let mut new_items = Vec::new();
     let mut chat_start_cmd: Option<String> = None;
     let mut chat_exit_cmd: Option<String> = None;
@@ -37,6 +40,7 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
                     chat_mode_start: chat_start,
                     chat_mode_exit: chat_exit,
                     llm_request: llm_req,
+                    allowed_roles,
                 } = get_command_attribute(&func.attrs);
                 if let Some(command) = cmd {
                     let func_name = &func.sig.ident;
@@ -48,6 +52,7 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
                         func_body.clone(),
                         chat_start,
                         chat_exit,
+                        allowed_roles,
                     ));
 
                     if chat_start == Some(true) {
@@ -60,6 +65,9 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
                         llm_request_cmd = Some(command_name);
                     }
                 }
+                if let Some((prefix, allowed_roles)) = get_callback_attribute(&func.attrs) {
+                    callbacks.push((prefix, func.sig.ident.clone(), allowed_roles));
+                }
                 new_items.push(syn::Item::Fn(func.clone()));
             }
             // Other items are pushed unchanged
@@ -76,7 +84,7 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
         panic!("chat_start, chat_exit and llm_request need to be either all or none defined");
     }
 
-    let handler_structs = commands.iter().map(|(command_name, _, _, _, _)| {
+    let handler_structs = commands.iter().map(|(command_name, ..)| {
         let struct_name = get_cmd_struct_name(command_name);
         quote! {
 
@@ -88,7 +96,7 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
 
     let handler_impls = commands
         .iter()
-        .map(|(command_name, func_name, _, chat_start, chat_exit)| {
+        .map(|(command_name, func_name, _, chat_start, chat_exit, allowed_roles)| {
             let struct_name = get_cmd_struct_name(command_name);
             let state = if chat_start == &Some(true) {
                 quote! {
@@ -105,19 +113,56 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
 
                 #[::async_trait::async_trait]
                 impl ::polybot::types::BotCommandHandler for #struct_name {
-                    async fn handle(&self, user: ::polybot::types::SharedUser, args: String) -> String {
+                    async fn handle(&self, user: ::polybot::types::SharedUser, args: String) -> ::polybot::types::BotReply {
                         #state
-                        #func_name(user, args).await
+                        // the handlers can either return a String or a BotReply.
+                        #func_name(user, args).await.into()
+                    }
+
+                    fn allowed_roles(&self) -> &'static [&'static str] {
+                        &[#(#allowed_roles),*]
                     }
                 }
             }
         });
 
-    let command_insert = commands.iter().map(|(command_name, _, _, _, _)| {
+    let command_insert = commands.iter().map(|(command_name, ..)| {
         let struct_name = get_cmd_struct_name(command_name);
         quote! { handlers.insert(#command_name.to_string(), Box::new(#struct_name))}
     });
 
+    let callback_structs = callbacks.iter().map(|(prefix, ..)| {
+        let struct_name = get_callback_struct_name(prefix);
+        quote! {
+
+            #[derive(Default)]
+            struct #struct_name;
+
+        }
+    });
+
+    let callback_impls = callbacks.iter().map(|(prefix, func_name, allowed_roles)| {
+        let struct_name = get_callback_struct_name(prefix);
+        quote! {
+
+            #[::async_trait::async_trait]
+            impl ::polybot::types::BotCommandHandler for #struct_name {
+                async fn handle(&self, user: ::polybot::types::SharedUser, args: String) -> ::polybot::types::BotReply {
+                    #func_name(user, args).await.into()
+                }
+
+                fn allowed_roles(&self) -> &'static [&'static str] {
+                    &[#(#allowed_roles),*]
+                }
+            }
+        }
+    });
+
+    let callback_insert = callbacks.iter().map(|(prefix, ..)| {
+        let struct_name = get_callback_struct_name(prefix);
+        quote! { handlers.insert(#prefix.to_string(), Box::new(#struct_name))}
+    });
+
     let chat_start = match chat_start_cmd {
         Some(val) => quote! {
              fn chat_start_command() -> Option<&'static str> {
@@ -168,6 +213,12 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
 
                 handlers
             }
+            fn callback_list() -> ::polybot::types::CommandHashMap {
+                let mut handlers: ::polybot::types::CommandHashMap = ::std::collections::HashMap::new();
+                #(#callback_insert;)*
+
+                handlers
+            }
             #chat_start
             #chat_exit
             #llm_request
@@ -179,7 +230,7 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
     let parsed_impl: ItemImpl = syn::parse2(bot_commands_impl)
         .expect("Failed to parse the BotCommands impl for MyCommands");
 
-    for handler_struct in handler_structs {
+    for handler_struct in handler_structs.chain(callback_structs) {
         let item = handler_struct.to_string();
 
         let struct_str: proc_macro2::TokenStream = item
@@ -192,7 +243,7 @@ pub fn bot_commands(_args: TokenStream, input: TokenStream) -> TokenStream {
         new_items.push(Item::Struct(struct_p));
     }
 
-    for handler_impl in handler_impls {
+    for handler_impl in handler_impls.chain(callback_impls) {
         let item = handler_impl.to_string();
 
         let impl_str: proc_macro2::TokenStream = item
@@ -232,6 +283,7 @@ fn get_command_attribute(attrs: &[Attribute]) -> CommandAttribute {
         chat_mode_start: None,
         chat_mode_exit: None,
         llm_request: None,
+        allowed_roles: vec![],
     };
     for attr in attrs {
         if attr.path.is_ident(CMD_ATTR) {
@@ -256,6 +308,10 @@ fn get_command_attribute(attrs: &[Attribute]) -> CommandAttribute {
                             if let Lit::Bool(lit_bool) = lit {
                                 cmd_attr.llm_request = Some(lit_bool.value());
                             }
+                        } else if path.is_ident("allow") {
+                            if let Lit::Str(lit_str) = lit {
+                                cmd_attr.allowed_roles = parse_roles(&lit_str.value());
+                            }
                         }
                     }
                 }
@@ -265,6 +321,42 @@ fn get_command_attribute(attrs: &[Attribute]) -> CommandAttribute {
     cmd_attr
 }
 
+/// Returns the prefix and the allowed roles of a callback handler.
+fn get_callback_attribute(attrs: &[Attribute]) -> Option<(String, Vec<String>)> {
+    let mut prefix = None;
+    let mut allowed_roles = vec![];
+    for attr in attrs {
+        if attr.path.is_ident(CALLBACK_ATTR) {
+            if let Ok(Meta::List(meta)) = attr.parse_meta() {
+                for nested in meta.nested {
+                    if let NestedMeta::Meta(Meta::NameValue(MetaNameValue {
+                        lit: Lit::Str(lit_str),
+                        path,
+                        ..
+                    })) = nested
+                    {
+                        if path.is_ident("prefix") {
+                            prefix = Some(lit_str.value());
+                        } else if path.is_ident("allow") {
+                            allowed_roles = parse_roles(&lit_str.value());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    prefix.map(|prefix| (prefix, allowed_roles))
+}
+
+/// Parses a comma separated list of roles, e.g "owner, family".
+fn parse_roles(roles: &str) -> Vec<String> {
+    roles
+        .split(',')
+        .map(|role| role.trim().to_string())
+        .filter(|role| !role.is_empty())
+        .collect()
+}
+
 #[proc_macro_attribute]
 pub fn handler(args: TokenStream, input: TokenStream) -> TokenStream {
     //validate the attribute,
@@ -282,6 +374,23 @@ pub fn handler(args: TokenStream, input: TokenStream) -> TokenStream {
     input
 }
 
+/// Marks a handler of the buttons whose callback data is "prefix" or "prefix:args",
+/// the handler gets the args part.
+#[proc_macro_attribute]
+pub fn callback(args: TokenStream, input: TokenStream) -> TokenStream {
+    let is_prefix_in_args = args.into_iter().any(|e| {
+        if let proc_macro::TokenTree::Ident(x) = e {
+            x.to_string() == *"prefix"
+        } else {
+            false
+        }
+    });
+    if !is_prefix_in_args {
+        panic!("Callback macro used without 'prefix'!");
+    }
+    input
+}
+
 fn to_camel_case(s: &str) -> String {
     let mut result = String::with_capacity(s.len());
     let mut upper = true;
@@ -302,3 +411,10 @@ fn get_cmd_struct_name(cmd: &str) -> Ident {
     let cmd_name = to_camel_case(cmd.trim_start_matches('/'));
     Ident::new(format!("{}Handler", cmd_name).as_str(), Span::call_site())
 }
+
+fn get_callback_struct_name(prefix: &str) -> Ident {
+    Ident::new(
+        format!("{}Callback", to_camel_case(prefix)).as_str(),
+        Span::call_site(),
+    )
+}
@@ -1,16 +1,29 @@
-use bot_commands_macro::{bot_commands, handler};
+use bot_commands_macro::{bot_commands, callback, handler};
+use polybot::queue::QueueStats;
+use polybot::supervisor::Supervisor;
+use std::sync::{Arc, OnceLock};
+
+/// The background services of the bot, given by `main` once the bot is created.
+pub static SERVICES: OnceLock<Arc<Supervisor>> = OnceLock::new();
+/// The counters of the updates, given by `main` once the bot is created.
+pub static UPDATES: OnceLock<Arc<QueueStats>> = OnceLock::new();
 
 #[bot_commands]
 pub mod commands {
 
     use super::*;
+    use polybot::discovery::IpDiscoverer;
+    use polybot::history::{IpHistory, HISTORY_FILE};
     use polybot::services::coinmarketcap::Coinmarket;
     use polybot::services::llm::{Agent, OpenAiModel};
     use polybot::services::openmeteo::OpenMeteo;
-    use polybot::types::{BotUserActions, WeatherProvider};
-    use polybot::utils::{get_affirmation, get_config, get_ip};
+    use polybot::types::{
+        BotReply, BotUserActions, Button, FileSource, IpFamily, Keyboard, WeatherProvider,
+    };
+    use polybot::utils::{certificate_expiry, get_affirmation, get_config};
     use rand::Rng;
     use std::io::Cursor;
+    use std::path::Path;
 
     #[handler(cmd = "/bitcoin")]
     async fn get_bitcoin(_user_tx: impl BotUserActions, _: String) -> String {
@@ -22,28 +35,116 @@ pub mod commands {
         "Error getting the bitcoin price".to_string()
     }
 
-    #[handler(cmd = "/ip")]
-    async fn ip(_user_tx: impl BotUserActions, _: String) -> String {
-        if let Ok(ip) = get_ip().await {
-            return ip;
+    #[handler(cmd = "/ip", allow = "owner")]
+    async fn ip(_user_tx: impl BotUserActions, args: String) -> String {
+        if args.trim() == "history" {
+            return ip_history().await;
+        }
+        let Ok(config) = get_config("config.toml").await else {
+            return "Error reading the config".to_string();
+        };
+        // found the same way as the monitor does.
+        let discovery = &config.ip_discovery;
+        let discover = |family| async move {
+            IpDiscoverer::from_config(discovery, family)
+                .discover(family)
+                .await
+        };
+        let Ok(ip) = discover(IpFamily::Ipv4).await else {
+            return "Error getting the Ip address".to_string();
+        };
+        match discover(IpFamily::Ipv6).await {
+            Ok(ipv6) => format!("{ip}\n{ipv6}"),
+            Err(_) => ip.to_string(),
+        }
+    }
+
+    async fn ip_history() -> String {
+        let Ok(config) = get_config("config.toml").await else {
+            return "Error reading the config".to_string();
+        };
+        let file = config
+            .ip_change
+            .history_file
+            .unwrap_or_else(|| HISTORY_FILE.into());
+        match IpHistory::load(&file).await {
+            Ok(records) if records.is_empty() => "No ip recorded yet.".to_string(),
+            Ok(records) => records
+                .iter()
+                .rev()
+                .map(|record| {
+                    format!(
+                        "{} since {}",
+                        record.ip,
+                        record.since.format("%Y-%m-%d %H:%M UTC")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+            Err(e) => format!("Error reading the ip history: {e}"),
         }
-        "Error getting the Ip address".to_string()
+    }
+
+    #[handler(cmd = "/status", allow = "owner")]
+    async fn status(_user_tx: impl BotUserActions, _: String) -> String {
+        let Ok(config) = get_config("config.toml").await else {
+            return "Error reading the config".to_string();
+        };
+        let mut status = match certificate_expiry(Path::new(&config.server.pubkey_path)) {
+            Ok(expiry) => format!(
+                "The certificate expires on {} (in {} days).",
+                expiry.format("%Y-%m-%d %H:%M UTC"),
+                (expiry - chrono::Utc::now()).num_days()
+            ),
+            Err(e) => format!("The certificate is unusable: {e}"),
+        };
+        if let Some(updates) = UPDATES.get() {
+            let metrics = updates.metrics();
+            status.push_str(&format!(
+                "\nUpdates: {} received, {} handled, {} waiting, {} rejected.",
+                metrics.enqueued, metrics.processed, metrics.depth, metrics.rejected
+            ));
+        }
+        let services = SERVICES.get().map(|services| services.statuses());
+        for (name, service) in services.unwrap_or_default() {
+            status.push_str(&format!("\n- {name}: {service}"));
+        }
+        status
     }
 
     #[handler(cmd = "/temp")]
-    async fn temp(_user_tx: impl BotUserActions, args: String) -> String {
+    async fn temp(_user_tx: impl BotUserActions, args: String) -> BotReply {
         let weather = OpenMeteo::new(None, "Lehnitz".to_string());
-        let mut city = weather.get_favourite_city();
-        if !args.is_empty() {
-            city = args.to_string();
+        if args.is_empty() {
+            // let the user pick the city
+            let cities = [
+                weather.get_favourite_city(),
+                "Berlin".into(),
+                "Bizerte".into(),
+            ];
+            let buttons = cities
+                .iter()
+                .map(|city| Button::callback(city, format!("temp:{city}")))
+                .collect();
+            return BotReply::text("Which city?").with_keyboard(Keyboard::new().row(buttons));
         }
-        if let Some(temp) = weather.get_temperature(city).await {
-            temp.to_string()
+        if let Some(temp) = weather.get_temperature(args).await {
+            temp.to_string().into()
         } else {
             "Error getting the temp".into()
         }
     }
 
+    #[callback(prefix = "temp")]
+    async fn temp_city(_user_tx: impl BotUserActions, city: String) -> BotReply {
+        let weather = OpenMeteo::new(None, "Lehnitz".to_string());
+        if let Some(temp) = weather.get_temperature(city.clone()).await {
+            BotReply::edit(format!("{city}: {temp}"))
+        } else {
+            BotReply::edit("Error getting the temp")
+        }
+    }
+
     #[handler(cmd = "/affirm")]
     async fn affirm(_user_tx: impl BotUserActions, _args: String) -> String {
         if let Ok(msg) = get_affirmation().await {
@@ -53,7 +154,7 @@ pub mod commands {
         }
     }
 
-    #[handler(cmd = "/ask")]
+    #[handler(cmd = "/ask", allow = "owner, llm")]
     async fn ask(_user_tx: impl BotUserActions, request: String) -> String {
         if request.is_empty() {
             return "Ask something!".to_string();
@@ -69,7 +170,7 @@ pub mod commands {
         }
     }
 
-    #[handler(cmd = "/chat", chat_start = true)]
+    #[handler(cmd = "/chat", chat_start = true, allow = "owner, llm")]
     async fn chat(user: impl BotUserActions, system_prompt: String) -> String {
         let mut prompt = system_prompt.as_str();
         if prompt.is_empty() {
@@ -83,7 +184,7 @@ pub mod commands {
     }
 
     /// Gives memory to your conversations in the chat mode
-    #[handler(cmd = "/chain", llm_request = true)]
+    #[handler(cmd = "/chain", llm_request = true, allow = "owner, llm")]
     async fn converse(user: impl BotUserActions, request: String) -> String {
         let conversation = user.get_conversation().await;
 
@@ -107,7 +208,7 @@ pub mod commands {
         rand::thread_rng().gen_range(1..=6).to_string()
     }
 
-    #[handler(cmd = "/docsearch")]
+    #[handler(cmd = "/docsearch", allow = "owner, llm")]
     async fn retrieval(_: impl BotUserActions, request: String) -> String {
         if let Ok(agent) = OpenAiModel::try_new() {
             if let Ok(answer) = agent.retrieval("mohamed", &request).await {
@@ -119,18 +220,23 @@ pub mod commands {
         }
     }
 
-    #[handler(cmd = "/url")]
-    async fn url(_: impl BotUserActions, request: String) -> String {
+    /// Sends back the text content of the page as a document.
+    #[handler(cmd = "/url", allow = "owner")]
+    async fn url(_: impl BotUserActions, request: String) -> BotReply {
         tracing::debug!("getting {}", request);
-        if let Ok(resp) = reqwest::get(request).await {
+        if let Ok(resp) = reqwest::get(&request).await {
             let body = resp.text().await.unwrap();
             let cursor = Cursor::new(body.into_bytes());
             let out = html2text::from_read(cursor, 200);
-            tracing::debug!("{out}");
-            // out
-            "printed it".to_string()
+            BotReply::Document {
+                document: FileSource::Bytes {
+                    file_name: "page.txt".to_string(),
+                    data: out.into_bytes(),
+                },
+                caption: Some(request),
+            }
         } else {
-            "Problem getting the url!".to_string()
+            "Problem getting the url!".into()
         }
     }
 }
@@ -0,0 +1,198 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::BotConfig;
+
+pub const OWNER_ROLE: &str = "owner";
+/// The callback prefix of the owner's Approve/Deny buttons.
+pub const APPROVAL_PREFIX: &str = "approval";
+/// Where the approvals are kept, if not configured otherwise.
+pub const APPROVALS_FILE: &str = "approvals.json";
+
+/// Whether a user that is not in the config can talk to the bot.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Approval {
+    Pending,
+    Approved,
+    Denied,
+}
+
+/// Decides who can run which command, based on the roles of the users.
+pub struct AccessControl {
+    roles: HashMap<String, HashSet<u64>>,
+    approvals: Mutex<HashMap<u64, Approval>>,
+    approvals_file: PathBuf,
+}
+
+impl AccessControl {
+    pub fn new(config: &BotConfig) -> Self {
+        let mut roles: HashMap<String, HashSet<u64>> = config
+            .roles
+            .iter()
+            .map(|(role, users)| (role.clone(), users.iter().copied().collect()))
+            .collect();
+        // the owner chat is the private chat with the owner, so it is their user id.
+        if let Ok(owner) = config.chat_id.parse::<u64>() {
+            roles
+                .entry(OWNER_ROLE.to_string())
+                .or_default()
+                .insert(owner);
+        }
+        let approvals_file = config
+            .approvals_file
+            .clone()
+            .unwrap_or_else(|| APPROVALS_FILE.into());
+        let approvals = Self::load_approvals(&approvals_file).unwrap_or_else(|e| {
+            error!("Could not load the approvals: {e:?}");
+            HashMap::new()
+        });
+        Self {
+            roles,
+            approvals: Mutex::new(approvals),
+            approvals_file,
+        }
+    }
+
+    fn load_approvals(path: &Path) -> Result<HashMap<u64, Approval>> {
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid {}", path.display()))
+    }
+
+    fn save_approvals(&self, approvals: &HashMap<u64, Approval>) -> Result<()> {
+        let path = &self.approvals_file;
+        std::fs::write(path, serde_json::to_string_pretty(approvals)?)
+            .with_context(|| format!("Could not write {}", path.display()))
+    }
+
+    fn is_member(&self, user_id: u64) -> bool {
+        self.roles.values().any(|users| users.contains(&user_id))
+    }
+
+    /// The users of the config (in any role) are approved from the start.
+    pub fn approval(&self, user_id: u64) -> Option<Approval> {
+        if self.is_member(user_id) {
+            return Some(Approval::Approved);
+        }
+        self.approvals
+            .lock()
+            .expect("poisoned lock")
+            .get(&user_id)
+            .copied()
+    }
+
+    /// Whether the owner was never asked about the user.
+    pub fn is_unknown(&self, user_id: u64) -> bool {
+        self.approval(user_id).is_none()
+    }
+
+    /// Puts the user in the pending state, once the owner has been asked about them.
+    pub fn set_pending(&self, user_id: u64) -> Result<()> {
+        let mut approvals = self.approvals.lock().expect("poisoned lock");
+        approvals.entry(user_id).or_insert(Approval::Pending);
+        self.save_approvals(&approvals)
+    }
+
+    /// Records the decision of the owner about the user.
+    pub fn decide(&self, user_id: u64, approved: bool) -> Result<()> {
+        let decision = if approved {
+            Approval::Approved
+        } else {
+            Approval::Denied
+        };
+        let mut approvals = self.approvals.lock().expect("poisoned lock");
+        approvals.insert(user_id, decision);
+        self.save_approvals(&approvals)
+    }
+
+    /// Whether the user has one of the `allowed` roles, an empty list allows everyone.
+    pub fn is_allowed(&self, user_id: u64, allowed: &[&str]) -> bool {
+        allowed.is_empty()
+            || allowed.iter().any(|role| {
+                self.roles
+                    .get(*role)
+                    .is_some_and(|users| users.contains(&user_id))
+            })
+    }
+
+    /// Checks that the user can run `command`, keeping a trace of the refusals.
+    pub fn authorize(
+        &self,
+        user_id: u64,
+        user_name: &str,
+        command: &str,
+        allowed: &[&str],
+    ) -> bool {
+        let authorized = self.is_allowed(user_id, allowed);
+        if !authorized {
+            warn!(
+                target: "audit",
+                user_id,
+                user_name,
+                command,
+                "refused the command, it requires one of the roles {:?}",
+                allowed
+            );
+        }
+        authorized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roles() {
+        let config = BotConfig {
+            chat_id: "1".to_string(),
+            roles: HashMap::from([("family".to_string(), vec![2, 3])]),
+            ..Default::default()
+        };
+        let access = AccessControl::new(&config);
+
+        assert!(access.is_allowed(4, &[]));
+        assert!(access.is_allowed(1, &["owner"]));
+        assert!(!access.is_allowed(2, &["owner"]));
+        assert!(access.is_allowed(2, &["owner", "family"]));
+        assert!(!access.is_allowed(4, &["owner", "family"]));
+        assert!(!access.is_allowed(1, &["unknown"]));
+    }
+
+    #[test]
+    fn test_approvals() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BotConfig {
+            chat_id: "1".to_string(),
+            approvals_file: Some(dir.path().join("approvals.json")),
+            ..Default::default()
+        };
+        let access = AccessControl::new(&config);
+
+        assert_eq!(access.approval(1), Some(Approval::Approved));
+        assert!(!access.is_unknown(1));
+        assert_eq!(access.approval(2), None);
+        assert!(access.is_unknown(2));
+        access.set_pending(2).unwrap();
+        assert!(!access.is_unknown(2));
+        assert_eq!(access.approval(2), Some(Approval::Pending));
+        access.set_pending(3).unwrap();
+        access.decide(2, true).unwrap();
+        access.decide(3, false).unwrap();
+
+        // the decisions survive a restart
+        let access = AccessControl::new(&config);
+        assert_eq!(access.approval(2), Some(Approval::Approved));
+        assert_eq!(access.approval(3), Some(Approval::Denied));
+        assert_eq!(access.approval(4), None);
+    }
+}
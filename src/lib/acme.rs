@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, App, HttpResponse, HttpServer};
+use anyhow::{bail, Context, Result};
+use openssl::base64;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::EcGroup;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder};
+use reqwest::header::{CONTENT_TYPE, LOCATION};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::fs;
+use tracing::{debug, info};
+
+use crate::types::{AcmeConfig, CertificateConfig, KeyAlgorithm};
+use crate::utils::{generate_key, write_private_key};
+
+const LETS_ENCRYPT: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const ACCOUNT_KEY: &str = "acme_account.key";
+const HTTP_PORT: u16 = 80;
+// delay and number of attempts when waiting for the CA.
+const POLL_DELAY: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 30;
+
+/// The url safe base64, without padding, used by the JWS.
+fn base64url(data: &[u8]) -> String {
+    base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+struct AcmeResponse {
+    location: Option<String>,
+    body: String,
+}
+
+/// The key authorizations served to the CA, by token.
+type Challenges = Arc<Mutex<HashMap<String, String>>>;
+
+async fn serve_challenge(
+    token: web::Path<String>,
+    challenges: web::Data<Challenges>,
+) -> HttpResponse {
+    match challenges
+        .lock()
+        .expect("poisoned lock")
+        .get(token.as_str())
+    {
+        Some(authorization) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(authorization.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Gets certificates from an ACME certificate authority (RFC 8555), proving the
+/// control of the domain with the HTTP-01 challenge.
+pub struct AcmeClient {
+    config: AcmeConfig,
+    client: reqwest::Client,
+    // the P-256 key of the account.
+    key: PKey<Private>,
+    directory: Directory,
+    nonce: Option<String>,
+    // the url of the account, once registered.
+    kid: Option<String>,
+}
+
+impl AcmeClient {
+    pub async fn new(config: AcmeConfig) -> Result<Self> {
+        let mut client = reqwest::Client::builder();
+        if let Some(ca_cert) = &config.ca_cert {
+            let pem = fs::read(ca_cert)
+                .await
+                .with_context(|| format!("Could not read {}", ca_cert.display()))?;
+            client = client.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        let client = client.build()?;
+
+        let key_path = config
+            .account_key_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(ACCOUNT_KEY));
+        let key = if key_path.exists() {
+            let pem = fs::read(&key_path).await?;
+            PKey::private_key_from_pem(&pem)
+                .with_context(|| format!("Invalid account key {}", key_path.display()))?
+        } else {
+            info!("Creating the ACME account key {}.", key_path.display());
+            let key = generate_key(KeyAlgorithm::EcdsaP256)?;
+            write_private_key(&key_path, &key).await?;
+            key
+        };
+
+        let url = config.directory_url.as_deref().unwrap_or(LETS_ENCRYPT);
+        let body = client
+            .get(url)
+            .send()
+            .await
+            .context("Could not get the ACME directory")?
+            .text()
+            .await?;
+        let directory = serde_json::from_str(&body)
+            .with_context(|| format!("Unexpected ACME directory: {body}"))?;
+
+        Ok(Self {
+            config,
+            client,
+            key,
+            directory,
+            nonce: None,
+            kid: None,
+        })
+    }
+
+    fn jwk(&self) -> Result<Value> {
+        let key = self.key.ec_key()?;
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+        let mut ctx = BigNumContext::new()?;
+        key.public_key()
+            .affine_coordinates(&group, &mut x, &mut y, &mut ctx)?;
+        // the members in lexicographic order, as required by the thumbprint.
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": base64url(&x.to_vec_padded(32)?),
+            "y": base64url(&y.to_vec_padded(32)?),
+        }))
+    }
+
+    /// What the challenge has to answer to prove that we own the account key.
+    fn key_authorization(&self, token: &str) -> Result<String> {
+        let jwk = serde_json::to_string(&self.jwk()?)?;
+        let thumbprint = hash(MessageDigest::sha256(), jwk.as_bytes())?;
+        Ok(format!("{token}.{}", base64url(&thumbprint)))
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let resp = self.client.head(&self.directory.new_nonce).send().await?;
+        resp.headers()
+            .get("Replay-Nonce")
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(str::to_string)
+            .context("No nonce given by the ACME server")
+    }
+
+    /// The JWS of the payload, signed with ES256.
+    fn sign(&self, url: &str, nonce: String, payload: Option<&Value>) -> Result<Value> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk()?,
+        }
+        let protected = base64url(serde_json::to_string(&protected)?.as_bytes());
+        // an empty payload for the POST-as-GET requests.
+        let payload = match payload {
+            Some(payload) => base64url(serde_json::to_string(payload)?.as_bytes()),
+            None => String::new(),
+        };
+
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key)?;
+        signer.update(format!("{protected}.{payload}").as_bytes())?;
+        // the JWS wants r and s concatenated, not the DER signature.
+        let signature = EcdsaSig::from_der(&signer.sign_to_vec()?)?;
+        let mut raw = signature.r().to_vec_padded(32)?;
+        raw.extend(signature.s().to_vec_padded(32)?);
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": base64url(&raw),
+        }))
+    }
+
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<AcmeResponse> {
+        // a rejected nonce can be retried once, with the fresh one of the error.
+        for retry in [false, true] {
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, nonce, payload)?;
+            let resp = self
+                .client
+                .post(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(serde_json::to_string(&body)?)
+                .send()
+                .await
+                .with_context(|| format!("Could not reach {url}"))?;
+            self.nonce = resp
+                .headers()
+                .get("Replay-Nonce")
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(str::to_string);
+            let location = resp
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .map(str::to_string);
+            let status = resp.status();
+            let body = resp.text().await?;
+            if status.is_success() {
+                return Ok(AcmeResponse { location, body });
+            }
+            if !retry && body.contains("urn:ietf:params:acme:error:badNonce") {
+                debug!("The ACME server rejected the nonce, retrying.");
+                continue;
+            }
+            bail!("ACME request to {url} failed ({status}): {body}");
+        }
+        unreachable!()
+    }
+
+    async fn post_as_get<T: for<'a> Deserialize<'a>>(&mut self, url: &str) -> Result<T> {
+        let body = self.post(url, None).await?.body;
+        serde_json::from_str(&body).with_context(|| format!("Unexpected ACME response: {body}"))
+    }
+
+    async fn register(&mut self) -> Result<()> {
+        if self.kid.is_some() {
+            return Ok(());
+        }
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if let Some(contact) = &self.config.contact {
+            account["contact"] = json!([format!("mailto:{contact}")]);
+        }
+        let url = self.directory.new_account.clone();
+        let resp = self.post(&url, Some(&account)).await?;
+        self.kid = Some(
+            resp.location
+                .context("No account url given by the ACME server")?,
+        );
+        Ok(())
+    }
+
+    /// Waits for the authorization to be validated by the CA.
+    async fn wait_authorization(&mut self, url: &str) -> Result<()> {
+        for _ in 0..POLL_ATTEMPTS {
+            let authorization: Authorization = self.post_as_get(url).await?;
+            match authorization.status.as_str() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => tokio::time::sleep(POLL_DELAY).await,
+                status => bail!("The authorization of {} is {status}", self.config.domain),
+            }
+        }
+        bail!("The authorization of {} timed out", self.config.domain)
+    }
+
+    /// Waits for the order to reach `status`.
+    async fn wait_order(&mut self, url: &str, status: &str) -> Result<Order> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.post_as_get(url).await?;
+            if order.status == status {
+                return Ok(order);
+            }
+            if order.status == "invalid" {
+                bail!("The order of {} is invalid", self.config.domain);
+            }
+            tokio::time::sleep(POLL_DELAY).await;
+        }
+        bail!("The order of {} timed out", self.config.domain)
+    }
+
+    /// Answers the HTTP-01 challenges of the order, from a temporary server.
+    async fn authorize(&mut self, order: &Order) -> Result<()> {
+        let challenges: Challenges = Arc::default();
+        let port = self.config.http_port.unwrap_or(HTTP_PORT);
+        let data = web::Data::new(challenges.clone());
+        let server = HttpServer::new(move || {
+            App::new().app_data(data.clone()).route(
+                "/.well-known/acme-challenge/{token}",
+                web::get().to(serve_challenge),
+            )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("0.0.0.0", port))
+        .with_context(|| format!("Could not listen on the port {port} for the challenge"))?
+        .run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let mut result = Ok(());
+        for url in &order.authorizations {
+            result = self.answer_challenge(url, &challenges).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        handle.stop(true).await;
+        result
+    }
+
+    async fn answer_challenge(&mut self, url: &str, challenges: &Challenges) -> Result<()> {
+        let authorization: Authorization = self.post_as_get(url).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == "http-01")
+            .context("The CA does not offer the HTTP-01 challenge")?;
+        challenges.lock().expect("poisoned lock").insert(
+            challenge.token.clone(),
+            self.key_authorization(&challenge.token)?,
+        );
+        // tells the CA that the challenge is ready.
+        self.post(&challenge.url, Some(&json!({}))).await?;
+        self.wait_authorization(url).await
+    }
+
+    fn csr(&self, key: &PKey<Private>, algorithm: KeyAlgorithm) -> Result<Vec<u8>> {
+        let mut builder = X509ReqBuilder::new()?;
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_text("CN", &self.config.domain)?;
+        builder.set_subject_name(&name.build())?;
+        builder.set_pubkey(key)?;
+        let mut extensions = Stack::new()?;
+        extensions.push(
+            SubjectAlternativeName::new()
+                .dns(&self.config.domain)
+                .build(&builder.x509v3_context(None))?,
+        )?;
+        builder.add_extensions(&extensions)?;
+        let digest = match algorithm {
+            KeyAlgorithm::Ed25519 => MessageDigest::null(),
+            _ => MessageDigest::sha256(),
+        };
+        builder.sign(key, digest)?;
+        Ok(builder.build().to_der()?)
+    }
+
+    /// Gets a certificate for the domain, written with its chain to `pubkey`.
+    pub async fn obtain_certificate(
+        &mut self,
+        pubkey: &Path,
+        privkey: &Path,
+        settings: &CertificateConfig,
+    ) -> Result<()> {
+        info!("Requesting a certificate for {} ...", self.config.domain);
+        self.register().await?;
+        let url = self.directory.new_order.clone();
+        let identifiers = json!({"identifiers": [{"type": "dns", "value": self.config.domain}]});
+        let resp = self.post(&url, Some(&identifiers)).await?;
+        let order_url = resp
+            .location
+            .context("No order url given by the ACME server")?;
+        let order: Order = serde_json::from_str(&resp.body)
+            .with_context(|| format!("Unexpected ACME order: {}", resp.body))?;
+
+        self.authorize(&order).await?;
+        self.wait_order(&order_url, "ready").await?;
+
+        let key = generate_key(settings.key)?;
+        let csr = json!({"csr": base64url(&self.csr(&key, settings.key)?)});
+        self.post(&order.finalize, Some(&csr)).await?;
+        let order = self.wait_order(&order_url, "valid").await?;
+        let certificate_url = order
+            .certificate
+            .context("No certificate url given by the ACME server")?;
+        let chain = self.post(&certificate_url, None).await?.body;
+
+        fs::write(pubkey, chain).await?;
+        write_private_key(privkey, &key).await?;
+        info!("Got the certificate of {}.", self.config.domain);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64url() {
+        assert_eq!(base64url(b"\xfb\xff"), "-_8");
+        assert_eq!(base64url(b"polybot"), "cG9seWJvdA");
+    }
+
+    /// Needs a local Pebble server (https://github.com/letsencrypt/pebble), run with
+    /// `PEBBLE_VA_ALWAYS_VALID=1` or with the domain resolving to this machine:
+    /// PEBBLE_CA=pebble.minica.pem cargo test acme -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_pebble() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = AcmeConfig {
+            domain: std::env::var("PEBBLE_DOMAIN").unwrap_or("polybot.localhost".to_string()),
+            directory_url: Some(
+                std::env::var("PEBBLE_DIRECTORY")
+                    .unwrap_or("https://localhost:14000/dir".to_string()),
+            ),
+            contact: None,
+            account_key_path: Some(dir.path().join("account.key")),
+            http_port: Some(5002),
+            ca_cert: std::env::var("PEBBLE_CA").ok().map(PathBuf::from),
+        };
+        let pubkey = dir.path().join("cert.pem");
+        let privkey = dir.path().join("key.pem");
+        let mut client = AcmeClient::new(config).await.unwrap();
+        client
+            .obtain_certificate(&pubkey, &privkey, &CertificateConfig::default())
+            .await
+            .unwrap();
+
+        let resolver = crate::tls::CertResolver::new(&pubkey, &privkey);
+        assert!(resolver.check().unwrap() > chrono::Utc::now());
+    }
+}
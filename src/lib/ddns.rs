@@ -0,0 +1,492 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde_json::{json, Value};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+use crate::types::{DdnsConfig, DdnsProviderConfig};
+
+const DUCKDNS_URL: &str = "https://www.duckdns.org";
+const CLOUDFLARE_URL: &str = "https://api.cloudflare.com/client/v4";
+const NSUPDATE_TTL: u32 = 300;
+const RETRIES: u32 = 3;
+// how long a provider has to answer, for each attempt.
+const TIMEOUT: Duration = Duration::from_secs(15);
+// doubled after each failed attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn record_type(ip: &IpAddr) -> &'static str {
+    match ip {
+        IpAddr::V4(_) => "A",
+        IpAddr::V6(_) => "AAAA",
+    }
+}
+
+/// Points a dns record to the public address.
+#[async_trait]
+pub trait DnsUpdater: Send + Sync {
+    fn name(&self) -> String;
+    async fn update(&self, ip: IpAddr) -> Result<()>;
+}
+
+pub struct DuckDns {
+    domains: Vec<String>,
+    token: String,
+    base_url: String,
+}
+
+impl DuckDns {
+    pub fn new(domains: Vec<String>, token: String, base_url: Option<String>) -> Self {
+        Self {
+            domains,
+            token,
+            base_url: base_url.unwrap_or_else(|| DUCKDNS_URL.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsUpdater for DuckDns {
+    fn name(&self) -> String {
+        format!("duckdns:{}", self.domains.join(","))
+    }
+
+    async fn update(&self, ip: IpAddr) -> Result<()> {
+        let param = if ip.is_ipv4() { "ip" } else { "ipv6" };
+        let answer = reqwest::Client::new()
+            .get(format!("{}/update", self.base_url))
+            .timeout(TIMEOUT)
+            .query(&[
+                ("domains", self.domains.join(",")),
+                ("token", self.token.clone()),
+                (param, ip.to_string()),
+            ])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            // the url carries the token.
+            .map_err(|e| e.without_url())?
+            .text()
+            .await?;
+        // "KO" when the token or the domains are wrong.
+        if !answer.trim().starts_with("OK") {
+            bail!("DuckDNS refused the update: {answer}");
+        }
+        Ok(())
+    }
+}
+
+pub struct Cloudflare {
+    api_token: String,
+    zone_id: String,
+    record: String,
+    base_url: String,
+}
+
+impl Cloudflare {
+    pub fn new(
+        api_token: String,
+        zone_id: String,
+        record: String,
+        base_url: Option<String>,
+    ) -> Self {
+        Self {
+            api_token,
+            zone_id,
+            record,
+            base_url: base_url.unwrap_or_else(|| CLOUDFLARE_URL.to_string()),
+        }
+    }
+
+    /// The `result` of an API answer, or its errors.
+    async fn request(&self, request: reqwest::RequestBuilder) -> Result<Value> {
+        let answer = request
+            .header(AUTHORIZATION, format!("Bearer {}", self.api_token))
+            .header(CONTENT_TYPE, "application/json")
+            .timeout(TIMEOUT)
+            .send()
+            .await?
+            .text()
+            .await?;
+        let answer: Value = serde_json::from_str(&answer)
+            .with_context(|| format!("Unexpected Cloudflare answer: {answer}"))?;
+        if answer["success"] != true {
+            bail!("Cloudflare refused the request: {}", answer["errors"]);
+        }
+        Ok(answer["result"].clone())
+    }
+}
+
+#[async_trait]
+impl DnsUpdater for Cloudflare {
+    fn name(&self) -> String {
+        format!("cloudflare:{}", self.record)
+    }
+
+    async fn update(&self, ip: IpAddr) -> Result<()> {
+        let client = reqwest::Client::new();
+        let records_url = format!("{}/zones/{}/dns_records", self.base_url, self.zone_id);
+        let records = self
+            .request(
+                client
+                    .get(&records_url)
+                    .query(&[("type", record_type(&ip)), ("name", &self.record)]),
+            )
+            .await?;
+        let id = records[0]["id"]
+            .as_str()
+            .with_context(|| format!("No {} record named {}", record_type(&ip), self.record))?;
+        if records[0]["content"] == ip.to_string() {
+            return Ok(());
+        }
+        self.request(
+            client
+                .patch(format!("{records_url}/{id}"))
+                .body(json!({ "content": ip.to_string() }).to_string()),
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+pub struct Nsupdate {
+    server: String,
+    zone: String,
+    record: String,
+    ttl: u32,
+    key_file: Option<PathBuf>,
+}
+
+impl Nsupdate {
+    pub fn new(
+        server: String,
+        zone: String,
+        record: String,
+        ttl: Option<u32>,
+        key_file: Option<PathBuf>,
+    ) -> Self {
+        Self {
+            server,
+            zone,
+            record,
+            ttl: ttl.unwrap_or(NSUPDATE_TTL),
+            key_file,
+        }
+    }
+
+    /// The commands given to `nsupdate`, replacing the record of the family.
+    fn script(&self, ip: IpAddr) -> String {
+        let (record, kind) = (&self.record, record_type(&ip));
+        format!(
+            "server {}\nzone {}\nupdate delete {record} {kind}\nupdate add {record} {} {kind} {ip}\nsend\n",
+            self.server, self.zone, self.ttl
+        )
+    }
+}
+
+#[async_trait]
+impl DnsUpdater for Nsupdate {
+    fn name(&self) -> String {
+        format!("nsupdate:{}", self.record)
+    }
+
+    async fn update(&self, ip: IpAddr) -> Result<()> {
+        let mut command = Command::new("nsupdate");
+        if let Some(key_file) = &self.key_file {
+            command.arg("-k").arg(key_file);
+        }
+        let mut child = command
+            // killed when it times out.
+            .kill_on_drop(true)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Could not run nsupdate")?;
+        let mut stdin = child.stdin.take().context("No stdin for nsupdate")?;
+        let script = self.script(ip);
+        let output = tokio::time::timeout(TIMEOUT, async move {
+            stdin.write_all(script.as_bytes()).await?;
+            // closing stdin ends the session.
+            drop(stdin);
+            child.wait_with_output().await
+        })
+        .await
+        .context("nsupdate timed out")??;
+        if !output.status.success() {
+            bail!(
+                "nsupdate failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Any service updated with a GET request, e.g "https://dyn.example.com/nic/update?myip={ip}".
+pub struct HttpTemplate {
+    url: String,
+}
+
+impl HttpTemplate {
+    pub fn new(url: String) -> Self {
+        Self { url }
+    }
+}
+
+#[async_trait]
+impl DnsUpdater for HttpTemplate {
+    fn name(&self) -> String {
+        // the url usually carries a secret.
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) => format!("http:{}", url.host_str().unwrap_or_default()),
+            Err(_) => "http".to_string(),
+        }
+    }
+
+    async fn update(&self, ip: IpAddr) -> Result<()> {
+        reqwest::Client::new()
+            .get(self.url.replace("{ip}", &ip.to_string()))
+            .timeout(TIMEOUT)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.without_url())?;
+        Ok(())
+    }
+}
+
+/// Updates all the configured records, trying again the failed ones.
+pub struct DdnsUpdater {
+    providers: Vec<Box<dyn DnsUpdater>>,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl DdnsUpdater {
+    pub fn new(providers: Vec<Box<dyn DnsUpdater>>, retries: u32, retry_delay: Duration) -> Self {
+        Self {
+            providers,
+            retries,
+            retry_delay,
+        }
+    }
+
+    pub fn from_config(config: &DdnsConfig) -> Self {
+        let providers = config
+            .providers
+            .iter()
+            .cloned()
+            .map(|provider| -> Box<dyn DnsUpdater> {
+                match provider {
+                    DdnsProviderConfig::DuckDns {
+                        domains,
+                        token,
+                        base_url,
+                    } => Box::new(DuckDns::new(domains, token, base_url)),
+                    DdnsProviderConfig::Cloudflare {
+                        api_token,
+                        zone_id,
+                        record,
+                        base_url,
+                    } => Box::new(Cloudflare::new(api_token, zone_id, record, base_url)),
+                    DdnsProviderConfig::Nsupdate {
+                        server,
+                        zone,
+                        record,
+                        ttl,
+                        key_file,
+                    } => Box::new(Nsupdate::new(server, zone, record, ttl, key_file)),
+                    DdnsProviderConfig::Http { url } => Box::new(HttpTemplate::new(url)),
+                }
+            })
+            .collect();
+        Self::new(providers, config.retries.unwrap_or(RETRIES), RETRY_DELAY)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    async fn update_with_retries(&self, provider: &dyn DnsUpdater, ip: IpAddr) -> Result<()> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 0;
+        loop {
+            match provider.update(ip).await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < self.retries => {
+                    warn!("Updating {} failed, trying again: {:?}", provider.name(), e);
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Points all the records to `ip`, returns the providers that still failed.
+    pub async fn update(&self, ip: IpAddr) -> Vec<(String, anyhow::Error)> {
+        let results = futures::future::join_all(
+            self.providers
+                .iter()
+                .map(|provider| self.update_with_retries(provider.as_ref(), ip)),
+        )
+        .await;
+        self.providers
+            .iter()
+            .zip(results)
+            .filter_map(|(provider, result)| match result {
+                Ok(()) => {
+                    info!("{} now points to {ip}", provider.name());
+                    None
+                }
+                Err(e) => Some((provider.name(), e)),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::Method::{GET, PATCH};
+    use httpmock::MockServer;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_duckdns() {
+        let server = MockServer::start();
+        let ok = server.mock(|when, then| {
+            when.method(GET)
+                .path("/update")
+                .query_param("domains", "mybot")
+                .query_param("token", "secret")
+                .query_param("ip", "192.0.2.1");
+            then.status(200).body("OK");
+        });
+        server.mock(|when, then| {
+            when.query_param("token", "wrong");
+            then.status(200).body("KO");
+        });
+
+        let duckdns = DuckDns::new(
+            vec!["mybot".into()],
+            "secret".into(),
+            Some(server.base_url()),
+        );
+        duckdns.update(ip("192.0.2.1")).await.unwrap();
+        ok.assert();
+        let duckdns = DuckDns::new(
+            vec!["mybot".into()],
+            "wrong".into(),
+            Some(server.base_url()),
+        );
+        assert!(duckdns.update(ip("192.0.2.1")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cloudflare() {
+        let server = MockServer::start();
+        let list = server.mock(|when, then| {
+            when.method(GET)
+                .path("/zones/zone/dns_records")
+                .query_param("type", "AAAA")
+                .query_param("name", "home.example.com")
+                .header("Authorization", "Bearer token");
+            then.status(200).body(
+                r#"{"success": true, "errors": [], "result": [{"id": "rec", "content": "2001:db8::1"}]}"#,
+            );
+        });
+        let patch = server.mock(|when, then| {
+            when.method(PATCH)
+                .path("/zones/zone/dns_records/rec")
+                .json_body(json!({"content": "2001:db8::2"}));
+            then.status(200)
+                .body(r#"{"success": true, "errors": [], "result": {"id": "rec"}}"#);
+        });
+
+        let cloudflare = Cloudflare::new(
+            "token".into(),
+            "zone".into(),
+            "home.example.com".into(),
+            Some(server.base_url()),
+        );
+        // already up to date.
+        cloudflare.update(ip("2001:db8::1")).await.unwrap();
+        patch.assert_hits(0);
+        cloudflare.update(ip("2001:db8::2")).await.unwrap();
+        list.assert_hits(2);
+        patch.assert();
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let server = MockServer::start();
+        let mut failing = server.mock(|when, then| {
+            when.path("/update").query_param("myip", "192.0.2.1");
+            then.status(500);
+        });
+        let template = format!("{}?myip={{ip}}", server.url("/update"));
+        let updater = DdnsUpdater::new(
+            vec![Box::new(HttpTemplate::new(template))],
+            2,
+            Duration::from_millis(1),
+        );
+
+        let failed = updater.update(ip("192.0.2.1")).await;
+        assert_eq!(failed.len(), 1);
+        failing.assert_hits(3);
+        failing.delete();
+        server.mock(|when, then| {
+            when.path("/update").query_param("myip", "192.0.2.1");
+            then.status(200);
+        });
+        assert!(updater.update(ip("192.0.2.1")).await.is_empty());
+    }
+
+    #[test]
+    fn test_provider_names() {
+        let config: DdnsConfig = toml::from_str(
+            r#"providers = [
+                { type = "duckdns", domains = ["mybot"], token = "t" },
+                { type = "nsupdate", server = "ns", zone = "z", record = "r" },
+            ]"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            config.providers[0],
+            DdnsProviderConfig::DuckDns { .. }
+        ));
+        assert!(matches!(
+            config.providers[1],
+            DdnsProviderConfig::Nsupdate { .. }
+        ));
+    }
+
+    #[test]
+    fn test_nsupdate_script() {
+        let nsupdate = Nsupdate::new(
+            "ns.example.com".into(),
+            "example.com".into(),
+            "home.example.com".into(),
+            None,
+            None,
+        );
+        assert_eq!(
+            nsupdate.script(ip("192.0.2.1")),
+            "server ns.example.com\nzone example.com\nupdate delete home.example.com A\n\
+            update add home.example.com 300 A 192.0.2.1\nsend\n"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use rand::Rng;
+use reqwest::header::CONTENT_TYPE;
+use tokio::net::{lookup_host, UdpSocket};
+use tracing::{debug, warn};
+
+use crate::types::{IpDiscoveryConfig, IpFamily, IpPolicy, IpProviderConfig};
+
+// how long a provider has to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+const STUN_MAGIC_COOKIE: u32 = 0x2112_A442;
+const NAT_PMP_PORT: u16 = 5351;
+
+fn matches_family(ip: &IpAddr, family: IpFamily) -> bool {
+    match family {
+        IpFamily::Ipv4 => ip.is_ipv4(),
+        IpFamily::Ipv6 => ip.is_ipv6(),
+    }
+}
+
+/// A way of finding the public address of the network.
+#[async_trait]
+pub trait IpDiscovery: Send + Sync {
+    fn name(&self) -> String;
+    async fn discover(&self, family: IpFamily) -> Result<IpAddr>;
+}
+
+/// An echo service, answering with the address of the caller.
+pub struct HttpEcho {
+    url: String,
+}
+
+impl HttpEcho {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self { url: url.into() }
+    }
+}
+
+#[async_trait]
+impl IpDiscovery for HttpEcho {
+    fn name(&self) -> String {
+        self.url.clone()
+    }
+
+    async fn discover(&self, _family: IpFamily) -> Result<IpAddr> {
+        let body = reqwest::Client::new()
+            .get(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        // e.g {"ip": "1.2.3.4"}, or the address alone.
+        let ip = match serde_json::from_str::<serde_json::Value>(&body) {
+            Ok(json) if json["ip"].is_string() => {
+                json["ip"].as_str().unwrap_or_default().to_string()
+            }
+            _ => body.trim().to_string(),
+        };
+        ip.parse()
+            .with_context(|| format!("Unexpected answer from {}: {body}", self.url))
+    }
+}
+
+/// Sends a binding request to a STUN server (RFC 5389).
+pub struct Stun {
+    server: String,
+}
+
+impl Stun {
+    pub fn new(server: impl Into<String>) -> Self {
+        Self {
+            server: server.into(),
+        }
+    }
+
+    /// The mapped address of a binding response.
+    fn parse_response(response: &[u8], transaction: &[u8; 12]) -> Result<IpAddr> {
+        if response.len() < 20 || response[0..2] != [0x01, 0x01] {
+            bail!("Not a STUN binding success response");
+        }
+        if &response[8..20] != transaction {
+            bail!("The STUN response is not the one of our request");
+        }
+        let length = u16::from_be_bytes([response[2], response[3]]) as usize;
+        let mut attributes = response
+            .get(20..20 + length)
+            .context("Truncated STUN response")?;
+        let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+        while attributes.len() >= 4 {
+            let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+            let size = u16::from_be_bytes([attributes[2], attributes[3]]) as usize;
+            let value = attributes
+                .get(4..4 + size)
+                .context("Truncated STUN attribute")?;
+            // XOR-MAPPED-ADDRESS, or the legacy MAPPED-ADDRESS.
+            if (kind == 0x0020 || kind == 0x0001) && value.len() >= 8 {
+                let xor = kind == 0x0020;
+                match value[1] {
+                    0x01 => {
+                        let mut octets: [u8; 4] = value[4..8].try_into()?;
+                        if xor {
+                            octets.iter_mut().zip(cookie).for_each(|(b, k)| *b ^= k);
+                        }
+                        return Ok(Ipv4Addr::from(octets).into());
+                    }
+                    0x02 if value.len() >= 20 => {
+                        let mut octets: [u8; 16] = value[4..20].try_into()?;
+                        if xor {
+                            let key = cookie.iter().chain(transaction);
+                            octets.iter_mut().zip(key).for_each(|(b, k)| *b ^= k);
+                        }
+                        return Ok(Ipv6Addr::from(octets).into());
+                    }
+                    _ => bail!("Unknown address family in the STUN response"),
+                }
+            }
+            // the attributes are padded to 4 bytes.
+            let next = (4 + size + 3) & !3;
+            attributes = attributes.get(next..).unwrap_or_default();
+        }
+        bail!("No mapped address in the STUN response")
+    }
+}
+
+#[async_trait]
+impl IpDiscovery for Stun {
+    fn name(&self) -> String {
+        format!("stun:{}", self.server)
+    }
+
+    async fn discover(&self, family: IpFamily) -> Result<IpAddr> {
+        let server = lookup_host(&self.server)
+            .await?
+            .find(|addr| matches_family(&addr.ip(), family))
+            .with_context(|| format!("No {family:?} address for {}", self.server))?;
+        let local: SocketAddr = match family {
+            IpFamily::Ipv4 => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            IpFamily::Ipv6 => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        let transaction: [u8; 12] = rand::thread_rng().gen();
+        // binding request, without attributes.
+        let mut request = vec![0x00, 0x01, 0x00, 0x00];
+        request.extend(STUN_MAGIC_COOKIE.to_be_bytes());
+        request.extend(transaction);
+        socket.send_to(&request, server).await?;
+
+        let mut response = [0; 512];
+        let (size, _) = socket.recv_from(&mut response).await?;
+        Self::parse_response(&response[..size], &transaction)
+    }
+}
+
+/// Asks the router its external address with NAT-PMP (RFC 6886), ipv4 only.
+pub struct NatPmp {
+    gateway: String,
+}
+
+impl NatPmp {
+    pub fn new(gateway: impl Into<String>) -> Self {
+        Self {
+            gateway: gateway.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl IpDiscovery for NatPmp {
+    fn name(&self) -> String {
+        format!("nat-pmp:{}", self.gateway)
+    }
+
+    async fn discover(&self, family: IpFamily) -> Result<IpAddr> {
+        if family != IpFamily::Ipv4 {
+            bail!("NAT-PMP only gives the ipv4 address");
+        }
+        let gateway = match self.gateway.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, NAT_PMP_PORT),
+            Err(_) => self.gateway.parse().context("Invalid NAT-PMP gateway")?,
+        };
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        socket.connect(gateway).await?;
+        // version 0, external address request.
+        socket.send(&[0, 0]).await?;
+
+        let mut response = [0; 16];
+        let size = socket.recv(&mut response).await?;
+        if size < 12 || response[..2] != [0, 128] {
+            bail!("Unexpected NAT-PMP response");
+        }
+        let result = u16::from_be_bytes([response[2], response[3]]);
+        if result != 0 {
+            bail!("The NAT-PMP request failed with the code {result}");
+        }
+        Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]).into())
+    }
+}
+
+/// Asks the router its external address through its UPnP WANIPConnection service.
+pub struct Upnp {
+    control_url: String,
+}
+
+impl Upnp {
+    pub fn new(control_url: impl Into<String>) -> Self {
+        Self {
+            control_url: control_url.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl IpDiscovery for Upnp {
+    fn name(&self) -> String {
+        format!("upnp:{}", self.control_url)
+    }
+
+    async fn discover(&self, _family: IpFamily) -> Result<IpAddr> {
+        const SERVICE: &str = "urn:schemas-upnp-org:service:WANIPConnection:1";
+        let body = format!(
+            r#"<?xml version="1.0"?><s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:GetExternalIPAddress xmlns:u="{SERVICE}"/></s:Body></s:Envelope>"#
+        );
+        let response = reqwest::Client::new()
+            .post(&self.control_url)
+            .header(CONTENT_TYPE, r#"text/xml; charset="utf-8""#)
+            .header("SOAPAction", format!(r#""{SERVICE}#GetExternalIPAddress""#))
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let ip = response
+            .split_once("<NewExternalIPAddress>")
+            .and_then(|(_, rest)| rest.split_once("</NewExternalIPAddress>"))
+            .map(|(ip, _)| ip.trim())
+            .with_context(|| format!("Unexpected UPnP response: {response}"))?;
+        ip.parse()
+            .with_context(|| format!("Invalid external address {ip:?}"))
+    }
+}
+
+/// The address of a local interface, e.g when the bot runs on the router.
+pub struct Interface {
+    name: String,
+}
+
+impl Interface {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+#[async_trait]
+impl IpDiscovery for Interface {
+    fn name(&self) -> String {
+        format!("interface:{}", self.name)
+    }
+
+    async fn discover(&self, family: IpFamily) -> Result<IpAddr> {
+        if_addrs::get_if_addrs()?
+            .into_iter()
+            .filter(|interface| interface.name == self.name)
+            .map(|interface| interface.ip())
+            // the link-local ipv6 addresses are not reachable from outside.
+            .filter(|ip| !matches!(ip, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80))
+            .find(|ip| matches_family(ip, family))
+            .with_context(|| format!("No {family:?} address on the interface {}", self.name))
+    }
+}
+
+/// Finds the public address with several providers, combined with a policy.
+pub struct IpDiscoverer {
+    policy: IpPolicy,
+    providers: Vec<Box<dyn IpDiscovery>>,
+}
+
+impl IpDiscoverer {
+    pub fn new(policy: IpPolicy, providers: Vec<Box<dyn IpDiscovery>>) -> Self {
+        Self { policy, providers }
+    }
+
+    /// The configured providers, or a few echo services of the family.
+    pub fn from_config(config: &IpDiscoveryConfig, family: IpFamily) -> Self {
+        let providers = if config.providers.is_empty() {
+            let urls: &[&str] = match family {
+                IpFamily::Ipv4 => &[
+                    "https://api.ipify.org?format=json",
+                    "https://ipv4.icanhazip.com",
+                    "https://checkip.amazonaws.com",
+                ],
+                IpFamily::Ipv6 => &[
+                    "https://api6.ipify.org?format=json",
+                    "https://ipv6.icanhazip.com",
+                ],
+            };
+            urls.iter()
+                .map(|url| Box::new(HttpEcho::new(*url)) as Box<dyn IpDiscovery>)
+                .collect()
+        } else {
+            config
+                .providers
+                .iter()
+                .map(|provider| -> Box<dyn IpDiscovery> {
+                    match provider {
+                        IpProviderConfig::Http { url } => Box::new(HttpEcho::new(url)),
+                        IpProviderConfig::Stun { server } => Box::new(Stun::new(server)),
+                        IpProviderConfig::NatPmp { gateway } => Box::new(NatPmp::new(gateway)),
+                        IpProviderConfig::Upnp { control_url } => Box::new(Upnp::new(control_url)),
+                        IpProviderConfig::Interface { name } => Box::new(Interface::new(name)),
+                    }
+                })
+                .collect()
+        };
+        Self::new(config.policy, providers)
+    }
+
+    async fn query(provider: &dyn IpDiscovery, family: IpFamily) -> Result<IpAddr> {
+        let ip = tokio::time::timeout(TIMEOUT, provider.discover(family))
+            .await
+            .with_context(|| format!("{} timed out", provider.name()))??;
+        if !matches_family(&ip, family) {
+            bail!("{} gave {ip}, which is not {family:?}", provider.name());
+        }
+        debug!("{} gave {ip}", provider.name());
+        Ok(ip)
+    }
+
+    pub async fn discover(&self, family: IpFamily) -> Result<IpAddr> {
+        match self.policy {
+            IpPolicy::FirstSuccess => {
+                for provider in &self.providers {
+                    match Self::query(provider.as_ref(), family).await {
+                        Ok(ip) => return Ok(ip),
+                        Err(e) => warn!("Could not get the ip from {}: {e:?}", provider.name()),
+                    }
+                }
+                bail!("None of the providers gave the {family:?} address")
+            }
+            IpPolicy::Majority => {
+                let answers = futures::future::join_all(
+                    self.providers
+                        .iter()
+                        .map(|provider| Self::query(provider.as_ref(), family)),
+                )
+                .await;
+                let mut votes: HashMap<IpAddr, usize> = HashMap::new();
+                for (provider, answer) in self.providers.iter().zip(answers) {
+                    match answer {
+                        Ok(ip) => *votes.entry(ip).or_default() += 1,
+                        Err(e) => warn!("Could not get the ip from {}: {e:?}", provider.name()),
+                    }
+                }
+                // more than half of the providers that answered.
+                let answered: usize = votes.values().sum();
+                votes
+                    .into_iter()
+                    .find(|(_, count)| *count * 2 > answered)
+                    .map(|(ip, _)| ip)
+                    .with_context(|| {
+                        format!("The providers do not agree on the {family:?} address")
+                    })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use httpmock::MockServer;
+
+    struct Fixed(Option<&'static str>);
+
+    #[async_trait]
+    impl IpDiscovery for Fixed {
+        fn name(&self) -> String {
+            format!("fixed:{:?}", self.0)
+        }
+
+        async fn discover(&self, _family: IpFamily) -> Result<IpAddr> {
+            Ok(self.0.context("unavailable")?.parse()?)
+        }
+    }
+
+    fn discoverer(policy: IpPolicy, answers: &[Option<&'static str>]) -> IpDiscoverer {
+        let providers = answers
+            .iter()
+            .map(|answer| Box::new(Fixed(*answer)) as Box<dyn IpDiscovery>)
+            .collect();
+        IpDiscoverer::new(policy, providers)
+    }
+
+    #[tokio::test]
+    async fn test_policies() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let v4 = IpFamily::Ipv4;
+        let first = discoverer(
+            IpPolicy::FirstSuccess,
+            &[None, Some("1.1.1.1"), Some("2.2.2.2")],
+        );
+        assert_eq!(first.discover(v4).await.unwrap(), ip("1.1.1.1"));
+
+        let majority = discoverer(
+            IpPolicy::Majority,
+            &[Some("1.1.1.1"), None, Some("2.2.2.2"), Some("2.2.2.2")],
+        );
+        assert_eq!(majority.discover(v4).await.unwrap(), ip("2.2.2.2"));
+
+        let tie = discoverer(IpPolicy::Majority, &[Some("1.1.1.1"), Some("2.2.2.2")]);
+        assert!(tie.discover(v4).await.is_err());
+        // an answer of the wrong family is ignored.
+        let wrong = discoverer(
+            IpPolicy::FirstSuccess,
+            &[Some("2001:db8::1"), Some("3.3.3.3")],
+        );
+        assert_eq!(wrong.discover(v4).await.unwrap(), ip("3.3.3.3"));
+    }
+
+    #[tokio::test]
+    async fn test_http_echo() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/json");
+            then.status(200).body(r#"{"ip": "192.0.2.1"}"#);
+        });
+        server.mock(|when, then| {
+            when.path("/text");
+            then.status(200).body("2001:db8::1\n");
+        });
+
+        let ip = HttpEcho::new(server.url("/json"))
+            .discover(IpFamily::Ipv4)
+            .await
+            .unwrap();
+        assert_eq!(ip, "192.0.2.1".parse::<IpAddr>().unwrap());
+        let ip = HttpEcho::new(server.url("/text"))
+            .discover(IpFamily::Ipv6)
+            .await
+            .unwrap();
+        assert_eq!(ip, "2001:db8::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_stun() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut request = [0; 20];
+            let (_, client) = server.recv_from(&mut request).await.unwrap();
+            // XOR-MAPPED-ADDRESS of 203.0.113.7:4242, after a SOFTWARE attribute.
+            let mut response = vec![0x01, 0x01, 0x00, 0x14];
+            response.extend(&request[4..20]);
+            response.extend([0x80, 0x22, 0x00, 0x01, b'x', 0, 0, 0]);
+            response.extend([0x00, 0x20, 0x00, 0x08, 0x00, 0x01]);
+            response.extend((4242u16 ^ 0x2112).to_be_bytes());
+            let cookie = STUN_MAGIC_COOKIE.to_be_bytes();
+            response.extend([203, 0, 113, 7].iter().zip(cookie).map(|(b, k)| b ^ k));
+            server.send_to(&response, client).await.unwrap();
+        });
+
+        let ip = Stun::new(address).discover(IpFamily::Ipv4).await.unwrap();
+        assert_eq!(ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_nat_pmp() {
+        let router = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let gateway = router.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut request = [0; 2];
+            let (_, client) = router.recv_from(&mut request).await.unwrap();
+            assert_eq!(request, [0, 0]);
+            let response = [0, 128, 0, 0, 0, 0, 1, 0, 198, 51, 100, 1];
+            router.send_to(&response, client).await.unwrap();
+        });
+
+        let ip = NatPmp::new(gateway).discover(IpFamily::Ipv4).await.unwrap();
+        assert_eq!(ip, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_upnp() {
+        let router = MockServer::start();
+        let mock = router.mock(|when, then| {
+            when.method(httpmock::Method::POST)
+                .path("/ctl/IPConn")
+                .header_exists("SOAPAction");
+            then.status(200).body(
+                "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                <NewExternalIPAddress>198.51.100.2</NewExternalIPAddress>\
+                </u:GetExternalIPAddressResponse></s:Body></s:Envelope>",
+            );
+        });
+
+        let ip = Upnp::new(router.url("/ctl/IPConn"))
+            .discover(IpFamily::Ipv4)
+            .await
+            .unwrap();
+        mock.assert();
+        assert_eq!(ip, "198.51.100.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_interface() {
+        let ip = Interface::new("lo").discover(IpFamily::Ipv4).await.unwrap();
+        assert_eq!(ip, "127.0.0.1".parse::<IpAddr>().unwrap());
+        assert!(Interface::new("missing0")
+            .discover(IpFamily::Ipv4)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, Semaphore};
+
+/// Runs the handlers of different chats concurrently, while the handlers of the
+/// same chat run one after the other, in their arrival order.
+pub struct Dispatcher {
+    // limits the number of handlers running at the same time.
+    permits: Semaphore,
+    // the tokio mutex is fair, its waiters get the lock in FIFO order.
+    chats: std::sync::Mutex<HashMap<i64, Arc<Mutex<()>>>>,
+}
+
+impl Dispatcher {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            permits: Semaphore::new(max_concurrent.max(1)),
+            chats: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Runs `handler` once the previous handlers of the chat are done, and a
+    /// slot is available.
+    pub async fn run<F: Future>(&self, chat_id: i64, handler: F) -> F::Output {
+        let chat = self
+            .chats
+            .lock()
+            .expect("poisoned lock")
+            .entry(chat_id)
+            .or_default()
+            .clone();
+        let turn = chat.lock().await;
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        let output = handler.await;
+        drop(permit);
+        drop(turn);
+
+        // forget the chat when no other handler is waiting for it.
+        let mut chats = self.chats.lock().expect("poisoned lock");
+        if Arc::strong_count(&chat) == 2 {
+            chats.remove(&chat_id);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::Barrier;
+    use tokio::time::{sleep, timeout};
+
+    #[tokio::test]
+    async fn test_chats_in_parallel() {
+        let dispatcher = Dispatcher::new(2);
+        // each handler can only finish when the other one is running too.
+        let barrier = Barrier::new(2);
+        let handler = |chat_id| {
+            dispatcher.run(chat_id, async {
+                barrier.wait().await;
+            })
+        };
+        timeout(
+            Duration::from_secs(1),
+            futures::future::join(handler(1), handler(2)),
+        )
+        .await
+        .expect("the chats were not served in parallel");
+    }
+
+    #[tokio::test]
+    async fn test_same_chat_in_order() {
+        let dispatcher = Dispatcher::new(2);
+        let handled = std::sync::Mutex::new(Vec::new());
+        let handler = |delay, id| {
+            let handled = &handled;
+            dispatcher.run(1, async move {
+                sleep(Duration::from_millis(delay)).await;
+                handled.lock().unwrap().push(id);
+            })
+        };
+        futures::future::join3(handler(50, 1), handler(0, 2), handler(10, 3)).await;
+
+        assert_eq!(*handled.lock().unwrap(), vec![1, 2, 3]);
+        assert!(dispatcher.chats.lock().unwrap().is_empty());
+    }
+}
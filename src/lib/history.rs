@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::error;
+
+use crate::types::IpChangeConfig;
+
+pub const HISTORY_FILE: &str = "ip_history.json";
+pub const HISTORY_SIZE: usize = 20;
+
+/// A public address, and when it was first seen.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IpRecord {
+    pub ip: String,
+    pub since: DateTime<Utc>,
+}
+
+/// The last public addresses, newest last, kept on disk across restarts.
+pub struct IpHistory {
+    capacity: usize,
+    records: VecDeque<IpRecord>,
+    file: PathBuf,
+}
+
+impl IpHistory {
+    pub async fn new(file: impl Into<PathBuf>, capacity: usize) -> Self {
+        let file = file.into();
+        let records = Self::load(&file).await.unwrap_or_else(|e| {
+            error!("Could not load the ip history: {e:?}");
+            vec![]
+        });
+        Self {
+            capacity,
+            records: records.into(),
+            file,
+        }
+    }
+
+    pub async fn from_config(config: &IpChangeConfig) -> Self {
+        Self::new(
+            config
+                .history_file
+                .clone()
+                .unwrap_or_else(|| HISTORY_FILE.into()),
+            config.history_size.unwrap_or(HISTORY_SIZE),
+        )
+        .await
+    }
+
+    /// The records of the file, newest last.
+    pub async fn load(file: &Path) -> Result<Vec<IpRecord>> {
+        if !file.exists() {
+            return Ok(vec![]);
+        }
+        let content = fs::read_to_string(file)
+            .await
+            .with_context(|| format!("Could not read {}", file.display()))?;
+        serde_json::from_str(&content).with_context(|| format!("Invalid {}", file.display()))
+    }
+
+    pub fn current(&self) -> Option<&IpRecord> {
+        self.records.back()
+    }
+
+    /// Records the address if it changed, returns the previous one in that case.
+    pub async fn record(&mut self, ip: &str) -> Result<Option<IpRecord>> {
+        let previous = match self.current() {
+            Some(current) if current.ip == ip => return Ok(None),
+            previous => previous.cloned(),
+        };
+        self.records.push_back(IpRecord {
+            ip: ip.to_string(),
+            since: Utc::now(),
+        });
+        while self.records.len() > self.capacity {
+            self.records.pop_front();
+        }
+        fs::write(&self.file, serde_json::to_string_pretty(&self.records)?)
+            .await
+            .with_context(|| format!("Could not write {}", self.file.display()))?;
+        Ok(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("history.json");
+        let mut history = IpHistory::new(&file, 2).await;
+
+        assert_eq!(history.record("1.1.1.1").await.unwrap(), None);
+        assert_eq!(history.record("1.1.1.1").await.unwrap(), None);
+        let previous = history.record("2.2.2.2").await.unwrap().unwrap();
+        assert_eq!(previous.ip, "1.1.1.1");
+        history.record("3.3.3.3").await.unwrap();
+
+        // only the last ones are kept, and survive a restart.
+        let records = IpHistory::load(&file).await.unwrap();
+        let ips: Vec<_> = records.iter().map(|record| record.ip.as_str()).collect();
+        assert_eq!(ips, ["2.2.2.2", "3.3.3.3"]);
+        let mut history = IpHistory::new(&file, 2).await;
+        assert_eq!(history.current().unwrap().ip, "3.3.3.3");
+        assert_eq!(history.record("3.3.3.3").await.unwrap(), None);
+    }
+}
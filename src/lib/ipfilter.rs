@@ -0,0 +1,170 @@
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::header::{HeaderMap, FORWARDED, X_FORWARDED_FOR};
+use ipnet::IpNet;
+
+/// Only lets the requests of the allowed networks in, the address of the client
+/// being read from the forwarding headers when the request comes from a trusted proxy.
+#[derive(Debug, Clone)]
+pub struct IpAllowlist {
+    allowed: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+/// The ipv4 clients of a dual-stack socket are seen as ipv4-mapped ipv6 addresses.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Parses "1.2.3.4", "1.2.3.4:80", "2001:db8::1" or "[2001:db8::1]:80".
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+/// The addresses of the `for` parameters of the `Forwarded` header (RFC 7239).
+fn forwarded_for(header: &str) -> Vec<Option<IpAddr>> {
+    header
+        .split(',')
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_node(value))
+                    .flatten()
+            })
+        })
+        .collect()
+}
+
+impl IpAllowlist {
+    pub fn new(allowed: Vec<IpNet>, trusted_proxies: Vec<IpNet>) -> Self {
+        Self {
+            allowed,
+            trusted_proxies,
+        }
+    }
+
+    fn contains(networks: &[IpNet], ip: IpAddr) -> bool {
+        networks.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The address of the client, the proxies are only trusted if listed.
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = canonical(peer);
+        if !Self::contains(&self.trusted_proxies, peer) {
+            return peer;
+        }
+        let chain = if let Some(header) = headers.get(FORWARDED) {
+            forwarded_for(header.to_str().unwrap_or_default())
+        } else if let Some(header) = headers.get(X_FORWARDED_FOR) {
+            header
+                .to_str()
+                .unwrap_or_default()
+                .split(',')
+                .map(parse_node)
+                .collect()
+        } else {
+            return peer;
+        };
+        // each proxy appends the address it received the request from, the
+        // client is the last one that is not a trusted proxy.
+        let mut client = peer;
+        for node in chain.into_iter().rev() {
+            match node.map(canonical) {
+                Some(ip) => {
+                    client = ip;
+                    if !Self::contains(&self.trusted_proxies, ip) {
+                        break;
+                    }
+                }
+                // hidden or malformed, we can't go further.
+                None => break,
+            }
+        }
+        client
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        Self::contains(&self.allowed, canonical(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    fn allowlist() -> IpAllowlist {
+        IpAllowlist::new(
+            vec![
+                "149.154.160.0/20".parse().unwrap(),
+                "91.108.4.0/22".parse().unwrap(),
+                "2001:b28:f23d::/48".parse().unwrap(),
+            ],
+            vec!["127.0.0.1/32".parse().unwrap(), "::1/128".parse().unwrap()],
+        )
+    }
+
+    #[test]
+    fn test_allowed_networks() {
+        let allowlist = allowlist();
+        for ip in [
+            "149.154.160.1",
+            "149.154.175.254",
+            "91.108.7.1",
+            "::ffff:149.154.167.99",
+            "2001:b28:f23d:f001::a",
+        ] {
+            assert!(allowlist.is_allowed(ip.parse().unwrap()), "{ip}");
+        }
+        // the old globs let "149.154.16?.*" through, i.e 149.154.16.x.
+        for ip in ["149.154.16.1", "149.154.176.1", "91.108.8.1", "2001:b28::1"] {
+            assert!(!allowlist.is_allowed(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn test_forwarded_client() {
+        let allowlist = allowlist();
+        let client = |peer: &str, header, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header, HeaderValue::from_static(value));
+            allowlist.client_ip(peer.parse().unwrap(), &headers)
+        };
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        assert_eq!(
+            client("127.0.0.1", X_FORWARDED_FOR, "1.1.1.1, 149.154.160.1"),
+            ip("149.154.160.1")
+        );
+        assert_eq!(
+            client("::1", X_FORWARDED_FOR, "149.154.160.1, 127.0.0.1"),
+            ip("149.154.160.1")
+        );
+        assert_eq!(
+            client(
+                "127.0.0.1",
+                FORWARDED,
+                "for=1.1.1.1, for=\"[2001:b28:f23d::1]:4711\";proto=https"
+            ),
+            ip("2001:b28:f23d::1")
+        );
+        // the headers of an untrusted peer are ignored.
+        assert_eq!(
+            client("8.8.8.8", X_FORWARDED_FOR, "149.154.160.1"),
+            ip("8.8.8.8")
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+use crate::ddns::DdnsUpdater;
+use crate::discovery::IpDiscoverer;
+use crate::history::IpHistory;
+use crate::supervisor::BackgroundService;
+use crate::{Bot, Config};
+
+/// Follows the public ip in both update modes: records its changes, tells the chats
+/// about them and points the dns records to it. The webhook monitor gets it from here.
+pub struct IpMonitor {
+    config: Config,
+    period: Duration,
+    current: watch::Sender<Option<String>>,
+}
+
+impl IpMonitor {
+    pub fn new(config: Config, period: Duration) -> Self {
+        Self {
+            config,
+            period,
+            current: watch::channel(None).0,
+        }
+    }
+
+    /// The last public ip found, `None` until the first one.
+    pub fn subscribe(&self) -> watch::Receiver<Option<String>> {
+        self.current.subscribe()
+    }
+
+    /// Records the current ip, and tells the configured chats when it changed.
+    async fn track_ip<B: Bot>(bot: &B, config: &Config, history: &mut IpHistory, ip: &str) {
+        let previous = match history.record(ip).await {
+            Ok(Some(previous)) => previous,
+            Ok(None) => return,
+            Err(e) => {
+                error!("Could not record the ip: {:?}", e);
+                return;
+            }
+        };
+        info!("The public ip changed from {} to {ip}", previous.ip);
+        let msg = format!(
+            "The public ip changed from {} (since {}) to {ip}.",
+            previous.ip,
+            previous.since.format("%Y-%m-%d %H:%M UTC")
+        );
+        let chats = config
+            .ip_change
+            .notify_chats
+            .clone()
+            .unwrap_or_else(|| vec![config.bot.chat_id.clone()]);
+        for chat in chats {
+            if let Err(e) = bot.send_message(&chat, &msg).await {
+                error!("Could not notify {chat} about the new ip: {:?}", e);
+            }
+        }
+    }
+
+    /// Points the dns records to the ip, returns whether all of them were updated. The
+    /// owner is told about the failures once per ip, the next rounds try again quietly.
+    async fn update_dns<B: Bot>(
+        bot: &B,
+        owner: &str,
+        ddns: &DdnsUpdater,
+        ip: &str,
+        report: bool,
+    ) -> bool {
+        let Ok(address) = ip.parse() else {
+            error!("Invalid ip {ip}, not updating the dns.");
+            return false;
+        };
+        let failed = ddns.update(address).await;
+        if failed.is_empty() {
+            return true;
+        }
+        let errors: Vec<String> = failed
+            .iter()
+            .map(|(name, e)| {
+                error!("Could not update {name}: {:?}", e);
+                format!("- {name}: {e}")
+            })
+            .collect();
+        if report {
+            let msg = format!(
+                "Could not point the dns records to {ip}:\n{}",
+                errors.join("\n")
+            );
+            if let Err(e) = bot.send_message(owner, &msg).await {
+                error!("Could not notify the owner: {:?}", e);
+            }
+        }
+        false
+    }
+}
+
+#[async_trait]
+impl<B: Bot> BackgroundService<B> for IpMonitor {
+    fn name(&self) -> String {
+        "ip monitor".to_string()
+    }
+
+    async fn run(&self, bot: Arc<B>) -> Result<()> {
+        let config = &self.config;
+        let family = config.server.ip_family;
+        let discoverer = IpDiscoverer::from_config(&config.ip_discovery, family);
+        let mut history = IpHistory::from_config(&config.ip_change).await;
+        let ddns = Arc::new(DdnsUpdater::from_config(&config.ddns));
+        // the ip the dns records point to, and the last one whose failure was reported.
+        let mut dns_ip: Option<String> = None;
+        let mut reported_ip: Option<String> = None;
+        // the update in progress, in its own task as the providers can be slow.
+        let mut dns_update: Option<(String, JoinHandle<bool>)> = None;
+
+        loop {
+            let current_ip = match discoverer.discover(family).await {
+                Ok(ip) => ip.to_string(),
+                Err(e) => {
+                    warn!("Could not get the public ip: {:?}", e);
+                    tokio::time::sleep(self.period).await;
+                    continue;
+                }
+            };
+            debug!("Current ip = {:?}", current_ip);
+            Self::track_ip(bot.as_ref(), config, &mut history, &current_ip).await;
+            self.current.send_replace(Some(current_ip.clone()));
+
+            if dns_update
+                .as_ref()
+                .is_some_and(|(_, update)| update.is_finished())
+            {
+                if let Some((ip, update)) = dns_update.take() {
+                    match update.await {
+                        Ok(true) => dns_ip = Some(ip),
+                        _ => reported_ip = Some(ip),
+                    }
+                }
+            }
+            if !ddns.is_empty() && dns_update.is_none() && dns_ip.as_ref() != Some(&current_ip) {
+                let report = reported_ip.as_ref() != Some(&current_ip);
+                let (bot, ddns, ip) = (bot.clone(), ddns.clone(), current_ip.clone());
+                let owner = config.bot.chat_id.clone();
+                let update = tokio::spawn(async move {
+                    Self::update_dns(bot.as_ref(), &owner, &ddns, &ip, report).await
+                });
+                dns_update = Some((current_ip, update));
+            }
+            tokio::time::sleep(self.period).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeBot};
+
+    #[tokio::test]
+    async fn test_ip_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("history.json");
+        let config = config(&format!(
+            "[ip_change]\nnotify_chats = [\"10\", \"-20\"]\nhistory_file = {:?}",
+            file
+        ));
+        let bot = FakeBot::default();
+        let mut history = IpHistory::from_config(&config.ip_change).await;
+
+        IpMonitor::track_ip(&bot, &config, &mut history, "192.0.2.1").await;
+        IpMonitor::track_ip(&bot, &config, &mut history, "192.0.2.1").await;
+        assert!(bot.sent().is_empty());
+
+        IpMonitor::track_ip(&bot, &config, &mut history, "192.0.2.2").await;
+        let sent = bot.sent();
+        let chats: Vec<_> = sent.iter().map(|(chat, _)| chat.as_str()).collect();
+        assert_eq!(chats, ["10", "-20"]);
+        for (_, msg) in sent {
+            assert!(msg.contains("from 192.0.2.1"));
+            assert!(msg.contains("to 192.0.2.2"));
+        }
+    }
+}
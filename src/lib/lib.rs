@@ -1,8 +1,21 @@
+pub mod access;
+pub mod acme;
+pub mod ddns;
+pub mod discovery;
+pub mod dispatcher;
+pub mod history;
+pub mod ipfilter;
+pub mod ipmonitor;
 pub mod server;
 pub mod telegram;
+pub mod tls;
 pub mod types;
 pub use types::{Bot, BotConfig, Config, ServerConfig};
 pub mod plant;
 pub mod polybot;
+pub mod queue;
 pub mod services;
+pub mod supervisor;
+#[cfg(test)]
+mod testing;
 pub mod utils;
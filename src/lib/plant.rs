@@ -1,8 +1,10 @@
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::supervisor::BackgroundService;
 use crate::Bot;
 use anyhow::Result;
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use influxdb::Client;
 use influxdb::InfluxDbWriteable;
@@ -115,3 +117,14 @@ impl PlantServer {
         }
     }
 }
+
+#[async_trait]
+impl<B: Bot> BackgroundService<B> for PlantServer {
+    fn name(&self) -> String {
+        "plant".to_string()
+    }
+
+    async fn run(&self, bot: Arc<B>) -> Result<()> {
+        self.start(bot).await
+    }
+}
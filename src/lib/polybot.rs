@@ -1,117 +1,426 @@
-use crate::plant::PlantServer;
+use crate::acme::AcmeClient;
+use crate::ipmonitor::IpMonitor;
+use crate::queue::{QueueMetrics, QueueStats, UpdateQueue};
 use crate::server::BotServer;
-use crate::utils::{generate_certificate, get_ip};
+use crate::supervisor::{BackgroundService, ServiceStatus, Supervisor};
+use crate::tls::CertResolver;
+use crate::types::UpdateMode;
+use crate::utils::{generate_certificate, webhook_url};
 use crate::{Bot, Config};
-use anyhow::Result;
-use std::path::PathBuf;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use std::{sync::Arc, time::Duration};
 use tokio::select;
-use tokio::sync::Notify;
-use tracing::{debug, error, info};
-
+use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+use tracing::{debug, error, info, warn};
 
 pub struct Polybot<B: Bot> {
     bot: Arc<B>,
     config: Config,
     webhook_monitor: Option<Duration>,
+    // the certificate of the server, reloaded when regenerated.
+    tls: Arc<CertResolver>,
+    services: Vec<Arc<dyn BackgroundService<B>>>,
+    supervisor: Arc<Supervisor>,
+    // the counters of the update queues, of the server and of the polling loop.
+    queue_stats: Arc<QueueStats>,
 }
 
 impl<B: Bot> Polybot<B> {
+    const POLL_RETRY: Duration = Duration::from_secs(5);
+    // delay before starting the server again when its certificate can't be loaded.
+    const SERVER_RETRY: Duration = Duration::from_secs(10);
+    // delay before trying the webhook again after falling back to polling.
+    const WEBHOOK_RETRY: Duration = Duration::from_secs(10 * 60);
+    const WEBHOOK_MAX_RETRY: Duration = Duration::from_secs(6 * 60 * 60);
+    // the certificate is renewed this many days before it expires, if not configured otherwise.
+    const RENEW_BEFORE_DAYS: u32 = 30;
+
     pub fn new(config: Config) -> Self {
         Self {
             bot: Arc::new(B::new(config.clone().bot)),
+            tls: Arc::new(CertResolver::new(
+                &config.server.pubkey_path,
+                &config.server.privkey_path,
+            )),
             config,
             webhook_monitor: None,
+            services: vec![],
+            supervisor: Arc::new(Supervisor::default()),
+            queue_stats: Arc::new(QueueStats::default()),
         }
     }
 
+    /// Runs the service alongside the bot, restarted on its own when it fails.
+    pub fn with_service(mut self, service: impl BackgroundService<B>) -> Self {
+        self.services.push(Arc::new(service));
+        self
+    }
+
+    /// The state of each background service.
+    pub fn services_status(&self) -> BTreeMap<String, ServiceStatus> {
+        self.supervisor.statuses()
+    }
+
+    /// The backpressure metrics of the updates, in both modes.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.queue_stats.metrics()
+    }
+
+    /// Follows the metrics of the updates once the bot is started (e.g for the commands).
+    pub fn queue_stats(&self) -> Arc<QueueStats> {
+        self.queue_stats.clone()
+    }
+
+    /// Follows the state of the background services once the bot is started (e.g for
+    /// the commands).
+    pub fn services(&self) -> Arc<Supervisor> {
+        self.supervisor.clone()
+    }
+
     /// Monitors each "timeout" period of time, the webhook ip address and the bot's
     /// current ip address, generates a new certificate with the updated ip, updating
-    /// the webhook right after.
+    /// the webhook right after. The ip changes are followed in polling mode too.
     pub fn with_webhook_monitoring(mut self, timeout: Duration) -> Self {
         self.webhook_monitor = Some(timeout);
         self
     }
 
-    /// Starts the main loop of the bot, starts the server, and the webhook monitoring
-    /// if enabled.
-    pub async fn start_loop(&self) -> Result<()> {
-        let bot_clone = self.bot.clone();
-        let conf_clone = self.config.clone();
-        let config_changed = Arc::new(Notify::new());
-        let config_changed_clone = config_changed.clone();
+    /// Long polls the updates from the bot provider, this replaces the server
+    /// when running in polling mode.
+    async fn poll_loop(&self) -> Result<()> {
+        // the updates can not be polled as long as a webhook is set.
+        self.bot.delete_webhook().await?;
+        self.bot.initialize().await?;
+        info!("Polling for updates ...");
+        // handled in the background like the webhook ones, the next batch is
+        // fetched meanwhile.
+        let queue = UpdateQueue::for_bot(
+            self.config
+                .server
+                .queue_size
+                .unwrap_or(BotServer::<B>::QUEUE_SIZE),
+            self.queue_stats.clone(),
+            self.bot.clone(),
+        );
+        loop {
+            match self.bot.poll_updates().await {
+                Ok(updates) => {
+                    for update in updates {
+                        // waits when the queue is full, the next updates are
+                        // kept by telegram meanwhile. They are acknowledged already,
+                        // so polling can't go on without the queue.
+                        if !queue.send(update).await {
+                            bail!("The update queue is closed!");
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("Polling the updates failed: {:?}", e);
+                    tokio::time::sleep(Self::POLL_RETRY).await;
+                }
+            }
+        }
+    }
 
-        if let Some(timeout) = self.webhook_monitor {
-            tokio::spawn(async move {
-                loop {
-                    // explicity handle the result as we are in async block
-                    if let Ok(current_ip) = get_ip().await {
-                        debug!("Current ip = {:?}", current_ip);
-                        if let Ok(configured) = bot_clone.is_webhook_configured(&current_ip).await {
-                            if !configured {
-                                info!("Certificate is not correclty configured, configuring ...");
-                            } else {
-                                // the webhook is already set
-                                tokio::time::sleep(timeout).await;
-                                continue;
-                            }
-                        } else {
-                            error!("Issue with getting the webhook status.");
+    /// Generates a new certificate for `ip` and uploads it along with the webhook. With
+    /// ACME, the certificate of the domain is only requested again when `renew` is set.
+    async fn configure_webhook(bot: &B, config: &Config, ip: &str, renew: bool) -> Result<()> {
+        let url = Self::webhook_url(config, ip)?;
+        if let Some(acme) = &config.server.acme {
+            if renew {
+                AcmeClient::new(acme.clone())
+                    .await
+                    .context("Could not reach the ACME server!")?
+                    .obtain_certificate(
+                        Path::new(&config.server.pubkey_path),
+                        Path::new(&config.server.privkey_path),
+                        &config.server.certificate,
+                    )
+                    .await
+                    .context("The ACME certificate request failed!")?;
+            }
+            bot.update_webhook(&url)
+                .await
+                .context("failed to set the webhook!")?;
+            return Ok(());
+        }
+        generate_certificate(
+            PathBuf::from(&config.server.pubkey_path),
+            PathBuf::from(&config.server.privkey_path),
+            ip,
+            &config.server.certificate,
+        )
+        .await
+        .context("The certificate generation failed!")?;
+        bot.update_webhook_cert(PathBuf::from(&config.server.pubkey_path), &url)
+            .await
+            .context("failed to upload the certificate!")?;
+        Ok(())
+    }
+
+    /// The url of the webhook, on the domain if there is one, on the ip otherwise.
+    fn webhook_url(config: &Config, ip: &str) -> Result<String> {
+        let host = config.server.acme.as_ref().map_or(ip, |acme| &acme.domain);
+        webhook_url(
+            host,
+            config.server.public_port,
+            &config.server.webhook_path(),
+        )
+    }
+
+    /// The new connections get the new certificate, the server is only restarted
+    /// if it can't be loaded.
+    fn reload_certificate(tls: &CertResolver, config_changed: &Notify) {
+        if let Err(e) = tls.reload() {
+            error!("Could not reload the certificate: {:?}", e);
+            config_changed.notify_one();
+        }
+    }
+
+    async fn notify_owner(bot: &B, config: &Config, msg: &str) {
+        if let Err(e) = bot.send_message(&config.bot.chat_id, msg).await {
+            error!("Could not notify the owner: {:?}", e);
+        }
+    }
+
+    /// The delay before trying the webhook again after a fallback, back to the shortest
+    /// one once the webhook stayed healthy for longer than the current delay.
+    fn retry_delay(retry_delay: Duration, webhook_set_at: DateTime<Utc>) -> Duration {
+        let healthy = (Utc::now() - webhook_set_at).to_std().unwrap_or_default();
+        if healthy > retry_delay {
+            Self::WEBHOOK_RETRY
+        } else {
+            retry_delay
+        }
+    }
+
+    /// Switches to polling if the webhook is failing since it was set, returns whether it did.
+    async fn check_webhook_health(
+        bot: &B,
+        config: &Config,
+        webhook_set_at: DateTime<Utc>,
+        mode: &watch::Sender<UpdateMode>,
+    ) -> Result<bool> {
+        let status = bot.get_webhook_status().await?;
+        let failing = status
+            .last_error
+            .filter(|(date, _)| *date > webhook_set_at && status.pending_updates > 0);
+        let Some((date, message)) = failing else {
+            return Ok(false);
+        };
+        warn!("The webhook is failing ({message}), switching to polling.");
+        mode.send_replace(UpdateMode::Polling);
+        Self::notify_owner(
+            bot,
+            config,
+            &format!(
+                "The webhook is failing since {date} with \"{message}\" ({} pending updates), switched to polling.",
+                status.pending_updates
+            ),
+        )
+        .await;
+        Ok(true)
+    }
+
+    /// Keeps the webhook configured with the current ip, and falls back to polling
+    /// as long as the bot provider can't deliver the updates to the webhook.
+    async fn monitor_webhook(
+        bot: Arc<B>,
+        config: Config,
+        timeout: Duration,
+        tls: Arc<CertResolver>,
+        config_changed: Arc<Notify>,
+        mode: Arc<watch::Sender<UpdateMode>>,
+        mut ip: watch::Receiver<Option<String>>,
+    ) {
+        // only the webhook errors that happened after this date are taken into account.
+        let mut webhook_set_at = Utc::now();
+        // set when we are polling because the webhook is failing.
+        let mut fallback_at: Option<Instant> = None;
+        let mut retry_delay = Self::WEBHOOK_RETRY;
+
+        loop {
+            // waits for the first ip, found by the ip monitor.
+            let current_ip = match ip.wait_for(Option::is_some).await {
+                Ok(current) => current.clone().unwrap_or_default(),
+                Err(_) => return,
+            };
+
+            let renew_before = chrono::Duration::days(
+                config
+                    .server
+                    .renew_before_days
+                    .unwrap_or(Self::RENEW_BEFORE_DAYS)
+                    .into(),
+            );
+            let renew = match tls.check() {
+                Ok(expiry) if expiry - Utc::now() > renew_before => false,
+                Ok(expiry) => {
+                    info!("The certificate expires on {expiry}, renewing it ...");
+                    true
+                }
+                Err(e) => {
+                    warn!("The certificate is unusable, regenerating it: {:?}", e);
+                    true
+                }
+            };
+
+            if let Some(since) = fallback_at {
+                if since.elapsed() >= retry_delay {
+                    info!("Trying to switch back to the webhook ...");
+                    match Self::configure_webhook(&bot, &config, &current_ip, renew).await {
+                        Ok(()) => {
+                            webhook_set_at = Utc::now();
+                            Self::reload_certificate(&tls, &config_changed);
+                            fallback_at = None;
+                            // wait longer before the next attempt, in case it is still failing.
+                            retry_delay = (retry_delay * 2).min(Self::WEBHOOK_MAX_RETRY);
+                            mode.send_replace(UpdateMode::Webhook);
+                            Self::notify_owner(&bot, &config, "Switched back to the webhook.")
+                                .await;
+                        }
+                        Err(e) => {
+                            error!("{:?}", e);
+                            fallback_at = Some(Instant::now());
                         }
+                    }
+                }
+                tokio::time::sleep(timeout).await;
+                continue;
+            }
 
-                        // generate new certificate
-                        if generate_certificate(
-                            PathBuf::from(conf_clone.clone().server.pubkey_path),
-                            PathBuf::from(conf_clone.clone().server.privkey_path),
-                            &current_ip,
-                            "Polybot",
-                        )
-                        .await
-                        .is_ok()
-                        {
-                            if bot_clone
-                                .update_webhook_cert(
-                                    PathBuf::from(conf_clone.clone().server.pubkey_path),
-                                    &current_ip,
-                                )
-                                .await
-                                .is_err()
-                            {
-                                error!("failed to upload the certificate!");
-                            } else {
-                                // notify the server that a new certificate has been uploaded
-                                config_changed_clone.notify_one();
-                            }
-                        } else {
-                            error!("The certificate generation failed!");
+            let configured = if renew {
+                Ok(false)
+            } else {
+                match Self::webhook_url(&config, &current_ip) {
+                    Ok(url) => bot.is_webhook_configured(&url).await,
+                    Err(e) => Err(e),
+                }
+            };
+            match configured {
+                Ok(true) => {
+                    match Self::check_webhook_health(&bot, &config, webhook_set_at, &mode).await {
+                        Ok(true) => fallback_at = Some(Instant::now()),
+                        Ok(false) => retry_delay = Self::retry_delay(retry_delay, webhook_set_at),
+                        Err(e) => error!("Issue with getting the webhook health: {:?}", e),
+                    }
+                }
+                configured => {
+                    if configured.is_ok() {
+                        info!("Certificate is not correclty configured, configuring ...");
+                    } else {
+                        error!("Issue with getting the webhook status.");
+                    }
+                    match Self::configure_webhook(&bot, &config, &current_ip, renew).await {
+                        Ok(()) => {
+                            webhook_set_at = Utc::now();
+                            Self::reload_certificate(&tls, &config_changed);
                         }
+                        Err(e) => error!("{:?}", e),
                     }
-                    tokio::time::sleep(timeout).await;
                 }
-            });
+            }
+            tokio::time::sleep(timeout).await;
         }
-        loop {
-            let mut server = BotServer::new(self.config.server.clone(), self.bot.clone());
-            let plant = PlantServer::new(
-                "192.168.2.214",
-                &self.config.bot.chat_id,
-                3333,
-                &self.config.bot.db_token,
+    }
+
+    /// Starts the main loop of the bot, starts the server (or the polling loop),
+    /// and the webhook monitoring if enabled.
+    pub async fn start_loop(&self) -> Result<()> {
+        let config_changed = Arc::new(Notify::new());
+        // the monitor switches between webhook and polling through this channel.
+        let (mode_tx, mut mode_rx) = watch::channel(self.config.bot.mode);
+        let mode_tx = Arc::new(mode_tx);
+
+        if let Some(timeout) = self.webhook_monitor {
+            // the ip is followed in both modes, the webhook only needs it in webhook mode.
+            let ip_monitor = IpMonitor::new(self.config.clone(), timeout);
+            let ip = ip_monitor.subscribe();
+            self.supervisor.spawn(
+                Arc::new(ip_monitor),
+                self.bot.clone(),
+                self.config.bot.chat_id.clone(),
+            );
+            if self.config.bot.mode == UpdateMode::Polling {
+                info!("Running in polling mode, the webhook monitoring is disabled.");
+            } else {
+                tokio::spawn(Self::monitor_webhook(
+                    self.bot.clone(),
+                    self.config.clone(),
+                    timeout,
+                    self.tls.clone(),
+                    config_changed.clone(),
+                    mode_tx.clone(),
+                    ip,
+                ));
+            }
+        }
+        for service in &self.services {
+            self.supervisor.spawn(
+                service.clone(),
+                self.bot.clone(),
+                self.config.bot.chat_id.clone(),
             );
+        }
+        loop {
+            let mode = *mode_rx.borrow_and_update();
+
+            if mode == UpdateMode::Polling {
+                select! {
+                    e = self.poll_loop() => {
+                        error!("Polling loop exited {:?}", e);
+                        break;
+                    }
+                    _ = mode_rx.changed() => {
+                        debug!("Switching from polling to {:?} mode ...", *mode_rx.borrow());
+                        continue;
+                    }
+                }
+            }
+
+            let mut server = match BotServer::new(
+                self.config.server.clone(),
+                self.bot.clone(),
+                self.tls.clone(),
+                self.queue_stats.clone(),
+            ) {
+                Ok(server) => server,
+                // the monitor (re)generates a missing or corrupt certificate meanwhile.
+                Err(e) if self.webhook_monitor.is_some() => {
+                    error!(
+                        "Could not start the server, retrying in {:?}: {:?}",
+                        Self::SERVER_RETRY,
+                        e
+                    );
+                    select! {
+                        _ = tokio::time::sleep(Self::SERVER_RETRY) => {}
+                        _ = config_changed.notified() => {}
+                        _ = mode_rx.changed() => {}
+                    }
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
 
             // the flow will block here, until one of the branches terminates, which is due to:
             // - The server terminates by itself (e.g crash ..)
-            // - The system's IP has changed
+            // - The regenerated certificate could not be reloaded
+            // - The webhook is failing and we fall back to polling
             select! {
                 _ = server.start() => {break;},
-                // A server restart needs to happen as the certificate has been changed.
+                // A server restart needs to happen as the configuration has been changed.
                 _ = config_changed.notified() => {
-                    debug!("Received certificate update notification, restarting server ...");
+                    debug!("Received configuration update notification, restarting server ...");
                     server.stop().await;
                     continue;
                 }
-                e = plant.start(self.bot.clone()) => {
-                    tracing::error!("Plant Server exited {:?}", e);
+                _ = mode_rx.changed() => {
+                    debug!("Switching from webhook to {:?} mode ...", *mode_rx.borrow());
+                    server.stop().await;
                     continue;
                 }
             }
@@ -119,3 +428,58 @@ impl<B: Bot> Polybot<B> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{config, FakeBot};
+    use crate::types::WebhookStatus;
+
+    #[test]
+    fn test_retry_backoff() {
+        let delay = Duration::from_secs(40 * 60);
+        let set_at = |minutes| Utc::now() - chrono::Duration::minutes(minutes);
+        // set again after a fallback, the webhook has no new errors yet.
+        assert_eq!(Polybot::<FakeBot>::retry_delay(delay, set_at(1)), delay);
+        assert_eq!(Polybot::<FakeBot>::retry_delay(delay, set_at(39)), delay);
+        assert_eq!(
+            Polybot::<FakeBot>::retry_delay(delay, set_at(41)),
+            Polybot::<FakeBot>::WEBHOOK_RETRY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_polling() {
+        let bot = FakeBot::default();
+        let config = config("");
+        let (mode, mode_rx) = watch::channel(UpdateMode::Webhook);
+        let set_at = Utc::now() - chrono::Duration::hours(1);
+        let failing = |minutes, pending_updates| WebhookStatus {
+            url: "https://127.0.0.1/".to_string(),
+            pending_updates,
+            last_error: Some((
+                set_at + chrono::Duration::minutes(minutes),
+                "Connection refused".to_string(),
+            )),
+        };
+
+        // an error from before the webhook was set, or without any update waiting.
+        *bot.webhook.lock().unwrap() = failing(-5, 3);
+        let health = Polybot::check_webhook_health(&bot, &config, set_at, &mode);
+        assert!(!health.await.unwrap());
+        *bot.webhook.lock().unwrap() = failing(5, 0);
+        let health = Polybot::check_webhook_health(&bot, &config, set_at, &mode);
+        assert!(!health.await.unwrap());
+        assert_eq!(*mode_rx.borrow(), UpdateMode::Webhook);
+        assert!(bot.sent().is_empty());
+
+        *bot.webhook.lock().unwrap() = failing(5, 3);
+        let health = Polybot::check_webhook_health(&bot, &config, set_at, &mode);
+        assert!(health.await.unwrap());
+        assert_eq!(*mode_rx.borrow(), UpdateMode::Polling);
+        let sent = bot.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0, "1");
+        assert!(sent[0].1.contains("Connection refused"));
+    }
+}
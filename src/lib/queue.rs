@@ -0,0 +1,367 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::FutureExt;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::Bot;
+
+/// The counters of the queues, shared by the successive ones (e.g across the
+/// server restarts) so that they can be followed.
+#[derive(Default)]
+pub struct QueueStats {
+    enqueued: AtomicU64,
+    rejected: AtomicU64,
+    processed: AtomicU64,
+    // accepted, but not being handled yet.
+    depth: AtomicUsize,
+}
+
+impl QueueStats {
+    pub fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            enqueued: self.enqueued.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            processed: self.processed.load(Ordering::Relaxed),
+            depth: self.depth.load(Ordering::Relaxed),
+        }
+    }
+
+    fn accepted(&self) {
+        self.enqueued.fetch_add(1, Ordering::Relaxed);
+        self.depth.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The backpressure metrics of the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueMetrics {
+    pub enqueued: u64,
+    /// Refused because the queue was full (or closed).
+    pub rejected: u64,
+    pub processed: u64,
+    /// Updates waiting to be handled.
+    pub depth: usize,
+}
+
+/// Bounded queue decoupling the reception of the updates from their handling.
+pub struct UpdateQueue {
+    // taken when draining, to stop accepting updates.
+    sender: Mutex<Option<mpsc::Sender<String>>>,
+    stats: Arc<QueueStats>,
+    consumer: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+}
+
+impl UpdateQueue {
+    /// Starts consuming the updates with `handler`, each one in its own task. The
+    /// updates of a same chat (as given by `chat`) are handled one after the other,
+    /// aside from the queue: a busy chat does not hold up the others. At most
+    /// `capacity` updates are waiting in the queue, as many for their chat, and as
+    /// many are being handled.
+    pub fn new<C, F, Fut>(capacity: usize, stats: Arc<QueueStats>, chat: C, handler: F) -> Self
+    where
+        C: Fn(&str) -> Option<i64> + Send + 'static,
+        F: Fn(String) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let capacity = capacity.max(1);
+        let (sender, mut receiver) = mpsc::channel::<String>(capacity);
+        let counters = stats.clone();
+        let consumer = tokio::spawn(async move {
+            // a panicking handler only loses its own update.
+            let handle = |chat_id: Option<i64>, update| {
+                counters.depth.fetch_sub(1, Ordering::Relaxed);
+                tokio::spawn(handler(update)).map(move |result| (chat_id, result))
+            };
+            let mut running = FuturesUnordered::new();
+            // the chats being served, with their next updates.
+            let mut chats: HashMap<i64, VecDeque<String>> = HashMap::new();
+            let mut waiting = 0;
+            let mut open = true;
+            while open || !running.is_empty() {
+                tokio::select! {
+                    update = receiver.recv(),
+                        if open && waiting < capacity && running.len() < capacity => match update {
+                        Some(update) => {
+                            let chat_id = chat(&update);
+                            match chat_id.and_then(|chat_id| chats.get_mut(&chat_id)) {
+                                Some(next) => {
+                                    next.push_back(update);
+                                    waiting += 1;
+                                }
+                                None => {
+                                    if let Some(chat_id) = chat_id {
+                                        chats.insert(chat_id, VecDeque::new());
+                                    }
+                                    running.push(handle(chat_id, update));
+                                }
+                            }
+                        }
+                        None => open = false,
+                    },
+                    Some((chat_id, result)) = running.next() => {
+                        counters.processed.fetch_add(1, Ordering::Relaxed);
+                        if let Err(e) = result {
+                            error!("The handler of an update failed: {e}");
+                        }
+                        let Some(chat_id) = chat_id else {
+                            continue;
+                        };
+                        match chats.get_mut(&chat_id).and_then(VecDeque::pop_front) {
+                            Some(update) => {
+                                waiting -= 1;
+                                running.push(handle(Some(chat_id), update));
+                            }
+                            None => {
+                                chats.remove(&chat_id);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            sender: Mutex::new(Some(sender)),
+            stats,
+            consumer: tokio::sync::Mutex::new(Some(consumer)),
+        }
+    }
+
+    /// A queue handing the updates to the bot.
+    pub fn for_bot<B: Bot>(capacity: usize, stats: Arc<QueueStats>, bot: Arc<B>) -> Self {
+        let chat_bot = bot.clone();
+        let chat = move |update: &str| chat_bot.update_chat_id(update);
+        Self::new(capacity, stats, chat, move |update: String| {
+            let bot = bot.clone();
+            async move {
+                if let Err(e) = bot.handle_message(update.clone()).await {
+                    error!(
+                        "Failed to handle the message! {} ({:?}), continuing anyway!",
+                        update, e
+                    );
+                }
+            }
+        })
+    }
+
+    /// Adds the update to the queue, waiting for room if it is full. Returns
+    /// false if the queue is closed.
+    pub async fn send(&self, update: String) -> bool {
+        let sender = self.sender.lock().expect("poisoned lock").clone();
+        let sent = match sender {
+            Some(sender) => sender.send(update).await.is_ok(),
+            None => false,
+        };
+        if sent {
+            self.stats.accepted();
+        } else {
+            self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        sent
+    }
+
+    /// Adds the update to the queue, returns false if it is full.
+    pub fn push(&self, update: String) -> bool {
+        let sender = self.sender.lock().expect("poisoned lock");
+        let result = match sender.as_ref() {
+            Some(sender) => sender.try_send(update),
+            None => Err(TrySendError::Closed(update)),
+        };
+        match result {
+            Ok(()) => {
+                self.stats.accepted();
+                true
+            }
+            Err(e) => {
+                self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Rejected an update, the queue is {}: {:?}",
+                    if matches!(e, TrySendError::Full(_)) {
+                        "full"
+                    } else {
+                        "closed"
+                    },
+                    self.metrics()
+                );
+                false
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> QueueMetrics {
+        self.stats.metrics()
+    }
+
+    /// Stops accepting updates, and waits (up to `timeout`) for the queued ones
+    /// to be handled.
+    pub async fn drain(&self, timeout: Duration) {
+        self.sender.lock().expect("poisoned lock").take();
+        let Some(consumer) = self.consumer.lock().await.take() else {
+            return;
+        };
+        match tokio::time::timeout(timeout, consumer).await {
+            Ok(_) => info!("The update queue is drained: {:?}", self.metrics()),
+            Err(_) => warn!(
+                "The update queue is not drained after {:?}, the remaining updates are handled in the background.",
+                timeout
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let release = Arc::new(Notify::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let queue = {
+            let release = release.clone();
+            let handled = handled.clone();
+            UpdateQueue::new(
+                1,
+                Default::default(),
+                |_| None,
+                move |update| {
+                    let release = release.clone();
+                    let handled = handled.clone();
+                    async move {
+                        release.notified().await;
+                        handled.lock().unwrap().push(update);
+                    }
+                },
+            )
+        };
+
+        // the first update is being handled, the second one waits in the queue.
+        assert!(queue.push("1".to_string()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(queue.push("2".to_string()));
+        assert!(!queue.push("3".to_string()));
+        assert_eq!(
+            queue.metrics(),
+            QueueMetrics {
+                enqueued: 2,
+                rejected: 1,
+                processed: 0,
+                depth: 1
+            }
+        );
+
+        release.notify_one();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        release.notify_one();
+        queue.drain(Duration::from_secs(1)).await;
+        assert_eq!(*handled.lock().unwrap(), vec!["1", "2"]);
+        assert_eq!(queue.metrics().processed, 2);
+        assert!(!queue.push("4".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_send_waits() {
+        let release = Arc::new(Notify::new());
+        let queue = Arc::new({
+            let release = release.clone();
+            UpdateQueue::new(
+                1,
+                Default::default(),
+                |_| None,
+                move |_| {
+                    let release = release.clone();
+                    async move { release.notified().await }
+                },
+            )
+        });
+
+        // one update is being handled, one is waiting, the third one waits for room.
+        assert!(queue.send("1".to_string()).await);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(queue.send("2".to_string()).await);
+        let third = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.send("3".to_string()).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!third.is_finished());
+
+        release.notify_one();
+        assert!(third.await.unwrap());
+        assert_eq!(queue.metrics().enqueued, 3);
+        assert_eq!(queue.metrics().rejected, 0);
+    }
+
+    #[tokio::test]
+    async fn test_busy_chat() {
+        let release = Arc::new(Notify::new());
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let queue = {
+            let release = release.clone();
+            let handled = handled.clone();
+            // the chat is the first character of the update.
+            let chat = |update: &str| update[..1].parse().ok();
+            UpdateQueue::new(3, Default::default(), chat, move |update: String| {
+                let release = release.clone();
+                let handled = handled.clone();
+                async move {
+                    if update.starts_with('1') {
+                        release.notified().await;
+                    }
+                    handled.lock().unwrap().push(update);
+                }
+            })
+        };
+
+        for update in ["1a", "1b", "1c", "2a"] {
+            assert!(queue.push(update.to_string()));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*handled.lock().unwrap(), vec!["2a"]);
+        assert_eq!(queue.metrics().depth, 2);
+
+        for _ in 0..3 {
+            release.notify_one();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        queue.drain(Duration::from_secs(1)).await;
+        assert_eq!(*handled.lock().unwrap(), vec!["2a", "1a", "1b", "1c"]);
+    }
+
+    #[tokio::test]
+    async fn test_handler_panic() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let queue = {
+            let handled = handled.clone();
+            UpdateQueue::new(
+                2,
+                Default::default(),
+                |_| Some(1),
+                move |update| {
+                    let handled = handled.clone();
+                    async move {
+                        assert_ne!(update, "boom");
+                        handled.lock().unwrap().push(update);
+                    }
+                },
+            )
+        };
+
+        assert!(queue.push("boom".to_string()));
+        assert!(queue.push("1".to_string()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the queue is still open after the panic.
+        assert!(queue.push("2".to_string()));
+        queue.drain(Duration::from_secs(1)).await;
+        assert_eq!(*handled.lock().unwrap(), vec!["1", "2"]);
+        assert_eq!(queue.metrics().processed, 3);
+        assert_eq!(queue.metrics().depth, 0);
+    }
+}
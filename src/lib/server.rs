@@ -1,69 +1,119 @@
+use crate::ipfilter::IpAllowlist;
+use crate::queue::{QueueStats, UpdateQueue};
+use crate::tls::CertResolver;
 use crate::ServerConfig;
 
 use crate::types::Bot;
-use actix_ip_filter::IPFilter;
 use actix_server::{Server, ServerHandle};
-use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
-use anyhow::Result;
-use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+use actix_web::dev::Service;
+use actix_web::error::ErrorForbidden;
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use anyhow::{Context, Result};
+use futures::future::{ready, Either};
+use openssl::memcmp;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::env;
-use std::net::{SocketAddr, TcpListener};
+use std::net::{IpAddr, SocketAddr, TcpListener};
 use std::sync::Arc;
-use tracing::{error, info};
+use std::time::Duration;
+use tracing::{error, info, warn};
 use tracing_actix_web::TracingLogger;
 
 pub struct BotServer<B: Bot + Send + Sync> {
     worker: Option<Server>,
     handle: ServerHandle,
+    queue: Arc<UpdateQueue>,
     pub bot: Arc<B>,
 }
 
-#[post("/")]
-async fn handler(body: web::Bytes, bot: web::Data<Arc<dyn Bot>>) -> impl Responder {
+// header holding the secret token of the webhook.
+const SECRET_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+
+/// Compares the tokens in constant time, any token is accepted if none is expected.
+fn is_authentic(expected: Option<&str>, received: Option<&[u8]>) -> bool {
+    match (expected, received) {
+        (None, _) => true,
+        (Some(expected), Some(received)) => {
+            expected.len() == received.len() && memcmp::eq(expected.as_bytes(), received)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+async fn handler(
+    req: HttpRequest,
+    body: web::Bytes,
+    bot: web::Data<Arc<dyn Bot>>,
+    queue: web::Data<Arc<UpdateQueue>>,
+) -> impl Responder {
+    let received = req
+        .headers()
+        .get(SECRET_HEADER)
+        .map(|token| token.as_bytes());
+    if !is_authentic(bot.webhook_secret().as_deref(), received) {
+        warn!(
+            "Rejected a request without the webhook secret token from {:?}.",
+            req.peer_addr()
+        );
+        return HttpResponse::Unauthorized();
+    }
     let update = if let Ok(msg) = String::from_utf8(body.to_vec()) {
         msg
     } else {
         error!("Wrong message format received! {:#?}", body.to_vec());
         return HttpResponse::BadRequest();
     };
-    if bot
-        .into_inner()
-        .handle_message(update.clone())
-        .await
-        .is_err()
-    {
-        error!(
-            "Failed to handle the message! {}, continuing anyway!",
-            update
-        );
+    // answer right away, telegram would send the update again otherwise.
+    if queue.push(update) {
+        HttpResponse::Ok()
+    } else {
+        // telegram retries later, when we had time to catch up.
+        HttpResponse::ServiceUnavailable()
     }
-    HttpResponse::Ok()
 }
 
 impl<B: Bot> BotServer<B> {
     const TIME_WAIT: u64 = 3;
-    pub fn new(config: ServerConfig, bot: Arc<B>) -> Self {
-        let mut priv_key = env::current_dir().unwrap();
-        priv_key.push(config.clone().privkey_path);
-        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap();
-        builder
-            .set_private_key_file(priv_key, SslFiletype::PEM)
-            .unwrap();
-
-        let mut pub_key = env::current_dir().unwrap();
-        pub_key.push(config.clone().pubkey_path);
-        builder.set_certificate_chain_file(pub_key).unwrap();
-        builder
-            .check_private_key()
-            .expect("failed to check the private key");
+    // updates that can be waiting to be handled, if not configured otherwise.
+    pub(crate) const QUEUE_SIZE: usize = 100;
+    // how long a restart waits for the queued updates to be handled.
+    const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+    pub fn new(
+        config: ServerConfig,
+        bot: Arc<B>,
+        tls: Arc<CertResolver>,
+        queue_stats: Arc<QueueStats>,
+    ) -> Result<Self> {
+        // the certificate is picked by the resolver at each handshake.
+        let builder = tls
+            .acceptor()
+            .context("The certificate of the server can't be loaded")?;
         let bot_clone = bot.clone();
         let bot_object: Arc<dyn Bot> = bot_clone;
+        let queue = Arc::new(UpdateQueue::for_bot(
+            config.queue_size.unwrap_or(Self::QUEUE_SIZE),
+            queue_stats,
+            bot.clone(),
+        ));
+        let server_queue = queue.clone();
+        let webhook_path = config.webhook_path();
+        let allowlist = IpAllowlist::new(
+            match config.allowed_ips.clone() {
+                Some(allowed) => allowed,
+                None => bot.get_webhook_ips().unwrap(),
+            },
+            config.trusted_proxies.clone(),
+        );
 
-        let addr: SocketAddr = format!("{}:{}", config.ip, config.port).parse().unwrap();
+        let ip: IpAddr = config.ip.parse().expect("Invalid server ip");
+        let addr = SocketAddr::new(ip, config.port as u16);
 
         // Setting up the socket
-        let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+        let socket =
+            Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP)).unwrap();
+        if addr.is_ipv6() {
+            // dual-stack unless told otherwise, the ipv4 clients are seen as ::ffff:a.b.c.d
+            socket.set_only_v6(config.ipv6_only).unwrap();
+        }
 
         // We use SO_REUSEADDR to prevent the case where the system did not release the
         // binded port in time. Since we are stopping the previous server instance  before starting
@@ -77,20 +127,36 @@ impl<B: Bot> BotServer<B> {
             let new_bot = bot_object.clone();
             App::new()
                 .app_data(web::Data::new(new_bot.clone()))
-                .service(handler)
+                .app_data(web::Data::new(server_queue.clone()))
+                .route(&webhook_path, web::post().to(handler))
+                .wrap_fn({
+                    let allowlist = allowlist.clone();
+                    move |req, srv| {
+                        let allowed = req.peer_addr().is_some_and(|peer| {
+                            let client = allowlist.client_ip(peer.ip(), req.headers());
+                            allowlist.is_allowed(client)
+                        });
+                        if allowed {
+                            Either::Left(srv.call(req))
+                        } else {
+                            warn!("Rejected a request from {:?}.", req.connection_info());
+                            Either::Right(ready(Err(ErrorForbidden("Forbidden"))))
+                        }
+                    }
+                })
                 .wrap(TracingLogger::default())
-                .wrap(IPFilter::new().allow(new_bot.get_webhook_ips().unwrap()))
         })
         .shutdown_timeout(Self::TIME_WAIT)
         .listen_openssl(listener, builder)
         .unwrap()
         .run();
 
-        BotServer {
+        Ok(BotServer {
             bot,
             handle: server.handle(),
+            queue,
             worker: Some(server),
-        }
+        })
     }
 
     pub async fn start(&mut self) -> Result<()> {
@@ -109,6 +175,36 @@ impl<B: Bot> BotServer<B> {
 
     pub async fn stop(&self) {
         info!("Stopping the server ..");
-        self.handle.stop(false).await;
+        self.handle.stop(true).await;
+        // the updates already acknowledged to telegram would be lost otherwise.
+        self.queue.drain(Self::DRAIN_TIMEOUT).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_token() {
+        assert!(is_authentic(None, None));
+        assert!(is_authentic(None, Some(b"anything")));
+        assert!(is_authentic(Some("secret"), Some(b"secret")));
+        assert!(!is_authentic(Some("secret"), Some(b"secreT")));
+        assert!(!is_authentic(Some("secret"), Some(b"secret2")));
+        assert!(!is_authentic(Some("secret"), None));
+    }
+
+    #[test]
+    fn test_missing_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let tls = CertResolver::new(dir.path().join("pub.pem"), dir.path().join("priv.pem"));
+        let server = BotServer::new(
+            crate::testing::config("").server,
+            Arc::new(crate::testing::FakeBot::default()),
+            Arc::new(tls),
+            Default::default(),
+        );
+        assert!(server.is_err());
     }
 }
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::Bot;
+
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+// a service running for this long is healthy again, its backoff is reset.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// A task running alongside the bot (e.g an MQTT client), restarted when it fails.
+#[async_trait]
+pub trait BackgroundService<B: Bot>: Send + Sync + 'static {
+    fn name(&self) -> String;
+    /// Runs until the service fails, or is done when it returns `Ok`.
+    async fn run(&self, bot: Arc<B>) -> Result<()>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceState {
+    Running,
+    /// Waiting before the next restart.
+    Failed {
+        error: String,
+        retry_at: DateTime<Utc>,
+    },
+    Stopped,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceStatus {
+    pub state: ServiceState,
+    pub restarts: u32,
+    pub since: DateTime<Utc>,
+}
+
+impl fmt::Display for ServiceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |date: &DateTime<Utc>| date.format("%Y-%m-%d %H:%M UTC").to_string();
+        match &self.state {
+            ServiceState::Running => write!(f, "running since {}", time(&self.since))?,
+            ServiceState::Failed { error, retry_at } => {
+                write!(f, "failed ({error}), restarting at {}", time(retry_at))?
+            }
+            ServiceState::Stopped => write!(f, "stopped since {}", time(&self.since))?,
+        }
+        if self.restarts > 0 {
+            write!(f, ", {} restarts", self.restarts)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs each service in its own task, restarting it with an exponential backoff.
+pub struct Supervisor {
+    backoff: Duration,
+    max_backoff: Duration,
+    statuses: Mutex<BTreeMap<String, ServiceStatus>>,
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self::new(BACKOFF, MAX_BACKOFF)
+    }
+}
+
+impl Supervisor {
+    pub fn new(backoff: Duration, max_backoff: Duration) -> Self {
+        Self {
+            backoff,
+            max_backoff,
+            statuses: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn set_state(&self, name: &str, state: ServiceState, restarts: u32) {
+        self.statuses.lock().unwrap().insert(
+            name.to_string(),
+            ServiceStatus {
+                state,
+                restarts,
+                since: Utc::now(),
+            },
+        );
+    }
+
+    /// The current state of each supervised service.
+    pub fn statuses(&self) -> BTreeMap<String, ServiceStatus> {
+        self.statuses.lock().unwrap().clone()
+    }
+
+    /// Starts the service, the owner (`chat_id`) is told when it goes down.
+    pub fn spawn<B: Bot>(
+        self: &Arc<Self>,
+        service: Arc<dyn BackgroundService<B>>,
+        bot: Arc<B>,
+        chat_id: String,
+    ) -> JoinHandle<()> {
+        let supervisor = self.clone();
+        tokio::spawn(async move {
+            let name = service.name();
+            let mut backoff = supervisor.backoff;
+            let mut restarts = 0;
+            loop {
+                info!("Starting the {name} service ...");
+                supervisor.set_state(&name, ServiceState::Running, restarts);
+                let started = Instant::now();
+                // in its own task, so that a panic is a failure like the others.
+                let run = {
+                    let (service, bot) = (service.clone(), bot.clone());
+                    tokio::spawn(async move { service.run(bot).await })
+                };
+                let error = match run.await {
+                    Ok(Ok(())) => {
+                        info!("The {name} service is done.");
+                        supervisor.set_state(&name, ServiceState::Stopped, restarts);
+                        return;
+                    }
+                    Ok(Err(e)) => e,
+                    Err(e) => anyhow!("panicked: {e}"),
+                };
+
+                if started.elapsed() >= STABLE_AFTER {
+                    backoff = supervisor.backoff;
+                }
+                error!("The {name} service failed, restarting in {backoff:?}: {error:?}");
+                let retry_at = Utc::now()
+                    + chrono::Duration::from_std(backoff).unwrap_or(chrono::Duration::zero());
+                supervisor.set_state(
+                    &name,
+                    ServiceState::Failed {
+                        error: error.to_string(),
+                        retry_at,
+                    },
+                    restarts,
+                );
+                // only the first failure of a row is reported.
+                if backoff == supervisor.backoff {
+                    let msg = format!("The {name} service failed ({error}), restarting it.");
+                    if let Err(e) = bot.send_message(&chat_id, &msg).await {
+                        warn!("Could not notify the owner: {:?}", e);
+                    }
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(supervisor.max_backoff);
+                restarts += 1;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::FakeBot;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// Fails `failures` times, then runs until the end of the test.
+    struct Flaky {
+        failures: u32,
+        runs: Arc<AtomicU32>,
+    }
+
+    #[async_trait]
+    impl<B: Bot> BackgroundService<B> for Flaky {
+        fn name(&self) -> String {
+            "flaky".to_string()
+        }
+
+        async fn run(&self, _bot: Arc<B>) -> Result<()> {
+            let run = self.runs.fetch_add(1, Ordering::SeqCst);
+            if run < self.failures {
+                anyhow::bail!("failure {run}");
+            }
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restarts() {
+        tokio::time::pause();
+        let bot = Arc::new(FakeBot::default());
+        let runs = Arc::new(AtomicU32::new(0));
+        let service = Arc::new(Flaky {
+            failures: 3,
+            runs: runs.clone(),
+        });
+        let supervisor = Arc::new(Supervisor::new(
+            Duration::from_millis(10),
+            Duration::from_millis(20),
+        ));
+
+        let handle = supervisor.spawn(service, bot.clone(), "42".to_string());
+        // the restarts wait 10ms, 20ms and 20ms.
+        for _ in 0..10 {
+            tokio::time::advance(Duration::from_millis(10)).await;
+            // lets the supervisor restart the service.
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+        let status = &supervisor.statuses()["flaky"];
+        assert_eq!(status.state, ServiceState::Running);
+        assert_eq!(status.restarts, 3);
+        assert!(status.to_string().ends_with(", 3 restarts"));
+        // the failures in a row are reported once.
+        assert_eq!(bot.sent().len(), 1);
+        handle.abort();
+    }
+}
@@ -1,52 +1,729 @@
 use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
 
-use crate::telegram::types::{Response, Update, Webhook};
+use crate::access::{AccessControl, Approval, APPROVAL_PREFIX, OWNER_ROLE};
+use crate::dispatcher::Dispatcher;
+use crate::telegram::dedup::UpdateTracker;
+use crate::telegram::replies::ReplyTracker;
+use crate::telegram::types::{CallbackQuery, ChatType, Message, Response, Update, Webhook};
 use crate::types::{
-    Bot, BotCommands, BotConfig, BotMessage, BotMessages, BotUser, BotUserActions, CommandHashMap,
-    SharedUsers,
+    Bot, BotCommands, BotConfig, BotMessage, BotMessages, BotReply, BotUser, BotUserActions,
+    Button, CommandHashMap, FileSource, Keyboard, ParseMode, SharedUser, SharedUsers,
+    WebhookStatus,
 };
+use crate::utils::{split_text, write_secret};
 use anyhow::{bail, Context, Ok, Result};
 use async_trait::async_trait;
+use ipnet::IpNet;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
 use reqwest::multipart::Part;
 use reqwest::{header::CONTENT_TYPE, multipart};
 use serde_json::json;
 use tokio::fs;
 use tokio::sync::Mutex;
-use tracing::debug;
+use tracing::{debug, error, info};
 
-use super::types::{BotCommand, BotCommandsParams, BotCommandsSet, SendMessage};
+use super::types::{BotCommand, BotCommandsParams, BotCommandsSet, EditMessageText, SendMessage};
 
 pub struct TelegramBot<B: BotCommands> {
     client: reqwest::Client,
     config: BotConfig,
     users: SharedUsers,
     command_list: CommandHashMap,
+    callback_list: CommandHashMap,
+    // id of the next update to request when polling.
+    offset: AtomicU64,
+    replies: std::sync::Mutex<ReplyTracker>,
+    updates: std::sync::Mutex<UpdateTracker>,
+    // one write of the last handled update at a time, so that the mark only moves up.
+    saving_update: Mutex<()>,
+    access: AccessControl,
+    dispatcher: Dispatcher,
+    // the secret_token of the webhook, sent back by telegram in every request.
+    secret: RwLock<Option<String>>,
     _commands: PhantomData<B>,
 }
 
 impl<B: BotCommands> TelegramBot<B> {
+    const ALLOWED_UPDATES: [&'static str; 3] = ["message", "edited_message", "callback_query"];
+    // long polling timeout in seconds.
+    const POLL_TIMEOUT: u64 = 30;
+    // in characters, as limited by telegram.
+    const MAX_MESSAGE_LEN: usize = 4096;
+    // number of answers that can be updated when their message is edited.
+    const TRACKED_REPLIES: usize = 1000;
+    // number of update ids remembered to drop the duplicates.
+    const TRACKED_UPDATES: usize = 1000;
+    // texts longer than this are sent as a file, if not configured otherwise.
+    const DOCUMENT_THRESHOLD: usize = 4 * Self::MAX_MESSAGE_LEN;
+    // handlers running at the same time, if not configured otherwise.
+    const MAX_CONCURRENT_HANDLERS: usize = 16;
+    // length of the generated webhook secret token.
+    const SECRET_LEN: usize = 32;
+    // where the generated secret is kept, if not configured otherwise.
+    const SECRET_FILE: &'static str = "webhook_secret";
+    const API_URL: &'static str = "https://api.telegram.org";
+
     pub fn get_token(&self) -> &str {
         &self.config.token
     }
 
-    async fn reply(&self, id: u64, msg: &str) -> Result<()> {
-        let url = format!(
-            "https://api.telegram.org/bot{}/sendMessage",
-            self.config.token
-        );
-        self.client
-            .post(url)
+    fn method_url(&self, method: &str) -> String {
+        let api_url = self.config.api_url.as_deref().unwrap_or(Self::API_URL);
+        format!("{}/bot{}/{}", api_url, self.config.token, method)
+    }
+
+    /// Returns the result of a bot api call, or its error description.
+    async fn api_result(method: &str, resp: reqwest::Response) -> Result<serde_json::Value> {
+        let body = resp.text().await?;
+        let mut json: serde_json::Value = serde_json::from_str(&body)
+            .with_context(|| format!("Unexpected {method} response: {body}"))?;
+        if json["ok"] != true {
+            bail!("{method} failed: {}", json["description"]);
+        }
+        Ok(json["result"].take())
+    }
+
+    async fn call_api(
+        &self,
+        method: &str,
+        payload: &impl serde::Serialize,
+    ) -> Result<serde_json::Value> {
+        let resp = self
+            .client
+            .post(self.method_url(method))
             .header(CONTENT_TYPE, "application/json")
-            .body(json!({"chat_id": id, "text": msg}).to_string())
+            .body(serde_json::to_string(payload)?)
+            .send()
+            .await
+            .with_context(|| format!("Could not call {method}"))?;
+        Self::api_result(method, resp).await
+    }
+
+    async fn call_api_multipart(
+        &self,
+        method: &str,
+        form: multipart::Form,
+    ) -> Result<serde_json::Value> {
+        let resp = self
+            .client
+            .post(self.method_url(method))
+            .multipart(form)
             .send()
             .await
+            .with_context(|| format!("Could not call {method}"))?;
+        Self::api_result(method, resp).await
+    }
+
+    /// Sends a photo or a document, `field` being the name of the file parameter.
+    async fn send_file(
+        &self,
+        method: &str,
+        field: &str,
+        chat_id: &str,
+        file: FileSource,
+        caption: Option<String>,
+    ) -> Result<serde_json::Value> {
+        match file {
+            FileSource::Url(url) => {
+                self.call_api(
+                    method,
+                    &json!({"chat_id": chat_id, field: url, "caption": caption}),
+                )
+                .await
+            }
+            FileSource::Bytes { file_name, data } => {
+                let mut form = multipart::Form::new()
+                    .text("chat_id", chat_id.to_string())
+                    .part(field.to_string(), Part::bytes(data).file_name(file_name));
+                if let Some(caption) = caption {
+                    form = form.text("caption", caption);
+                }
+                self.call_api_multipart(method, form).await
+            }
+        }
+    }
+
+    /// Cuts the long texts in several messages, the ones above the document
+    /// threshold are sent as a text file instead.
+    fn fit_text(
+        &self,
+        text: String,
+        parse_mode: Option<ParseMode>,
+        keyboard: Option<Keyboard>,
+    ) -> Vec<BotReply> {
+        let length = text.chars().count();
+        if length <= Self::MAX_MESSAGE_LEN {
+            return vec![BotReply::Text {
+                text,
+                parse_mode,
+                keyboard,
+            }];
+        }
+        let threshold = self
+            .config
+            .document_threshold
+            .unwrap_or(Self::DOCUMENT_THRESHOLD);
+        if length > threshold {
+            debug!("Sending a {length} characters long text as a document.");
+            return vec![BotReply::Document {
+                document: FileSource::Bytes {
+                    file_name: "answer.txt".to_string(),
+                    data: text.into_bytes(),
+                },
+                caption: None,
+            }];
+        }
+
+        let mut chunks: Vec<BotReply> = split_text(&text, Self::MAX_MESSAGE_LEN)
+            .into_iter()
+            .map(|chunk| BotReply::Text {
+                text: chunk,
+                parse_mode,
+                keyboard: None,
+            })
+            .collect();
+        // the buttons go below the last part.
+        if let Some(last) = chunks.pop() {
+            chunks.push(match keyboard {
+                Some(keyboard) => last.with_keyboard(keyboard),
+                None => last,
+            });
+        }
+        chunks
+    }
+
+    /// Sends the reply to the chat `chat_id`, `editable` being the message that
+    /// `BotReply::Edit` replaces, returns the ids of the sent messages.
+    async fn reply(
+        &self,
+        chat_id: &str,
+        reply: BotReply,
+        editable: Option<u64>,
+    ) -> Result<Vec<u64>> {
+        let mut messages = vec![];
+        for message in reply.into_messages() {
+            let too_long = |text: &str| text.chars().count() > Self::MAX_MESSAGE_LEN;
+            messages.extend(match message {
+                BotReply::Text {
+                    text,
+                    parse_mode,
+                    keyboard,
+                } => self.fit_text(text, parse_mode, keyboard),
+                // nothing to edit, or too long to be edited, send it as new messages.
+                BotReply::Edit {
+                    text,
+                    parse_mode,
+                    keyboard,
+                } if editable.is_none() || too_long(&text) => {
+                    self.fit_text(text, parse_mode, keyboard)
+                }
+                message => vec![message],
+            });
+        }
+        let mut ids = vec![];
+        for message in messages {
+            let sent = match message {
+                BotReply::Text {
+                    text,
+                    parse_mode,
+                    keyboard,
+                } => {
+                    let payload = SendMessage {
+                        chat_id: chat_id.to_string(),
+                        text,
+                        parse_mode,
+                        reply_markup: keyboard.map(Into::into),
+                        ..Default::default()
+                    };
+                    self.call_api("sendMessage", &payload).await
+                }
+                BotReply::Edit {
+                    text,
+                    parse_mode,
+                    keyboard,
+                } => {
+                    let payload = EditMessageText {
+                        chat_id: chat_id.to_string(),
+                        message_id: editable.unwrap_or_default(),
+                        text,
+                        parse_mode,
+                        reply_markup: keyboard.map(Into::into),
+                    };
+                    match self.call_api("editMessageText", &payload).await {
+                        // the text is the same as before, which is fine.
+                        Err(e) if e.to_string().contains("message is not modified") => {
+                            Ok(json!({"message_id": payload.message_id}))
+                        }
+                        result => result,
+                    }
+                }
+                BotReply::Photo { photo, caption } => {
+                    self.send_file("sendPhoto", "photo", chat_id, photo, caption)
+                        .await
+                }
+                BotReply::Document { document, caption } => {
+                    self.send_file("sendDocument", "document", chat_id, document, caption)
+                        .await
+                }
+                BotReply::Location {
+                    latitude,
+                    longitude,
+                } => {
+                    self.call_api(
+                        "sendLocation",
+                        &json!({"chat_id": chat_id, "latitude": latitude, "longitude": longitude}),
+                    )
+                    .await
+                }
+                BotReply::Multiple(_) | BotReply::NoReply => unreachable!("flattened reply"),
+            }
             .context("Could not send the reply")?;
+            ids.extend(sent["message_id"].as_u64());
+        }
+        Ok(ids)
+    }
+
+    /// Replaces the `previous` answer with the new one, editing it in place when
+    /// possible. Returns the ids of the new answer messages.
+    async fn replace_reply(
+        &self,
+        chat_id: &str,
+        previous: Vec<u64>,
+        answer: BotReply,
+    ) -> Result<Vec<u64>> {
+        let ids = match answer {
+            BotReply::Text {
+                text,
+                parse_mode,
+                keyboard,
+            } => {
+                let edit = BotReply::Edit {
+                    text,
+                    parse_mode,
+                    keyboard,
+                };
+                self.reply(chat_id, edit, previous.first().copied()).await?
+            }
+            answer => self.reply(chat_id, answer, None).await?,
+        };
+
+        // whatever was not edited of the previous answer is outdated.
+        for message_id in previous.into_iter().filter(|id| !ids.contains(id)) {
+            if let Err(e) = self
+                .call_api(
+                    "deleteMessage",
+                    &json!({"chat_id": chat_id, "message_id": message_id}),
+                )
+                .await
+            {
+                error!("Could not delete the outdated answer {message_id}: {e:?}");
+            }
+        }
+        Ok(ids)
+    }
+
+    async fn get_user(&self, chat_id: i64, user_id: u64, user_name: &str) -> SharedUser {
+        let mut user = self
+            .users
+            .lock()
+            .await
+            .entry((chat_id, user_id))
+            .or_insert_with(|| {
+                // add the user in the hashmap
+                debug!(
+                    "Adding the user (id = {}), (name = {}) of the chat {}.",
+                    user_id, user_name, chat_id
+                );
+                Arc::new(RwLock::new(BotUser::new()))
+            })
+            .clone();
+
+        // update the user activity
+        user.set_last_activity(chrono::Utc::now()).await;
+        user
+    }
+
+    /// Asks the owner whether the unknown user can use the bot.
+    async fn request_approval(&self, user_id: u64, user_name: &str) -> Result<()> {
+        if !self.access.is_unknown(user_id) {
+            return Ok(());
+        }
+        info!("The user {user_name} ({user_id}) is waiting for approval.");
+        let keyboard = Keyboard::new().row(vec![
+            Button::callback("Approve", format!("{APPROVAL_PREFIX}:approve:{user_id}")),
+            Button::callback("Deny", format!("{APPROVAL_PREFIX}:deny:{user_id}")),
+        ]);
+        let request = BotReply::text(format!(
+            "The user {user_name} ({user_id}) wants to use the bot."
+        ))
+        .with_keyboard(keyboard);
+        // the owner is asked again with the next message, until the owner gets the request.
+        self.reply(&self.config.chat_id, request, None).await?;
+        self.access.set_pending(user_id)
+    }
+
+    /// Applies the owner's answer to an approval request.
+    async fn handle_approval(&self, query: &CallbackQuery, argument: &str) -> Result<()> {
+        let (decision, user_id) = argument.split_once(':').unwrap_or((argument, ""));
+        let user_id: u64 = user_id
+            .parse()
+            .with_context(|| format!("Invalid approval request {argument:?}"))?;
+        let approved = decision == "approve";
+        self.access.decide(user_id, approved)?;
+        let (status, notice) = if approved {
+            ("approved", "The owner approved your access, welcome!")
+        } else {
+            ("denied", "The owner denied your access.")
+        };
+        info!("The user {user_id} is {status} by the owner.");
+        if let Some(message) = &query.message {
+            self.reply(
+                &message.chat.id.to_string(),
+                BotReply::edit(format!("The user {user_id} is {status}.")),
+                Some(message.message_id),
+            )
+            .await?;
+        }
+        if let Err(e) = self.reply(&user_id.to_string(), notice.into(), None).await {
+            error!("Could not notify the user {user_id}: {e:?}");
+        }
+        Ok(())
+    }
+
+    async fn handle_callback(&self, query: CallbackQuery) -> Result<()> {
+        let data = query.data.clone().unwrap_or_default();
+        let (prefix, argument) = data.split_once(':').unwrap_or((&data, ""));
+        debug!("Callback: {:?}, Arg: {:?}", prefix, argument);
+        let (user_id, user_name) = (query.from.id, &query.from.first_name);
+        let allowed = if prefix == APPROVAL_PREFIX {
+            self.access
+                .authorize(user_id, user_name, APPROVAL_PREFIX, &[OWNER_ROLE])
+        } else if self.access.approval(user_id) != Some(Approval::Approved) {
+            info!("Refusing the callback of the unapproved user {user_id}.");
+            false
+        } else {
+            self.callback_list.get(prefix).is_none_or(|handler| {
+                self.access
+                    .authorize(user_id, user_name, &data, handler.allowed_roles())
+            })
+        };
+        // stops the loading animation of the button, the refusals are shown to the user.
+        let mut answer = json!({"callback_query_id": query.id});
+        if !allowed {
+            answer["text"] = "You are not allowed to do this.".into();
+        }
+        if let Err(e) = self.call_api("answerCallbackQuery", &answer).await {
+            error!("Could not answer the callback query {}: {e:?}", query.id);
+        }
+        if !allowed {
+            return Ok(());
+        }
+
+        if prefix == APPROVAL_PREFIX {
+            return self.handle_approval(&query, argument).await;
+        }
+        let Some(handler) = self.callback_list.get(prefix) else {
+            info!("No handler for the callback data {:?}.", data);
+            return Ok(());
+        };
+        let chat_id = query
+            .message
+            .as_ref()
+            .map_or(query.from.id as i64, |message| message.chat.id);
+        let user = self
+            .get_user(chat_id, query.from.id, &query.from.first_name)
+            .await;
+        let answer = handler.handle(user, argument.to_string()).await;
+        match query.message {
+            Some(message) => {
+                self.reply(
+                    &message.chat.id.to_string(),
+                    answer,
+                    Some(message.message_id),
+                )
+                .await
+            }
+            // the message is gone, answer in the private chat of the user.
+            None => self.reply(&query.from.id.to_string(), answer, None).await,
+        }
+        .map(|_| ())
+    }
+
+    /// Keeps the id of the handled update, to drop its duplicates after a restart.
+    async fn save_handled(&self, update_id: u64) {
+        let _saving = self.saving_update.lock().await;
+        let handled = self
+            .updates
+            .lock()
+            .expect("poisoned lock")
+            .handled(update_id);
+        if let Some((file, mark)) = handled {
+            if let Err(e) = fs::write(&file, mark.to_string()).await {
+                error!("Could not save the last update id: {e}");
+            }
+        }
+    }
+
+    fn bot_name(&self) -> &str {
+        self.config.name.trim_start_matches('@')
+    }
+
+    /// Removes the "@botname" suffix of the commands sent in groups, returns None
+    /// when the command is addressed to another bot.
+    fn strip_mention<'a>(&self, command: &'a str) -> Option<&'a str> {
+        if !command.starts_with('/') {
+            return Some(command);
+        }
+        match command.split_once('@') {
+            Some((cmd, name)) if name.eq_ignore_ascii_case(self.bot_name()) => Some(cmd),
+            Some(_) => None,
+            None => Some(command),
+        }
+    }
+
+    /// Whether the text is a command for the bot, or for any bot ("/cmd").
+    fn is_command(&self, text: &str) -> bool {
+        text.split_whitespace()
+            .next()
+            .is_some_and(|cmd| cmd.starts_with('/') && self.strip_mention(cmd).is_some())
+    }
+
+    /// Whether the message mentions the bot, or replies to one of its messages.
+    fn is_addressed(&self, message: &Message) -> bool {
+        let mention = format!("@{}", self.bot_name());
+        // the offsets of the entities are in utf-16 code units.
+        let text: Vec<u16> = message.get_message().encode_utf16().collect();
+        let mentioned = message
+            .entities
+            .iter()
+            .flatten()
+            .filter(|entity| entity.entity_type == "mention")
+            .filter_map(|entity| text.get(entity.offset..entity.offset + entity.length))
+            .any(|name| String::from_utf16_lossy(name).eq_ignore_ascii_case(&mention));
+        let replied = message
+            .reply_to_message
+            .as_ref()
+            .and_then(|replied| replied.from.as_ref())
+            .and_then(|author| author.username.as_deref())
+            .is_some_and(|name| name.eq_ignore_ascii_case(self.bot_name()));
+        mentioned || replied
+    }
+
+    /// Answers the update, called by the dispatcher once it is the turn of its chat.
+    async fn handle_update(&self, mut update: Update) -> Result<()> {
+        let answer: BotReply;
+        let id: i64;
+        let message_id: u64;
+        if let Some(query) = update.callback_query {
+            return self.handle_callback(query).await;
+        }
+        // an edited message is handled again, its answer replacing the previous one.
+        let edited = update.message.is_none() && update.edited_message.is_some();
+        if let Some(message) = update.message.take().or(update.edited_message.take()) {
+            message_id = message.message_id;
+            let private = message.chat.chat_type == ChatType::Private;
+            let mentioned = self.is_addressed(&message);
+            if !private && self.config.mention_only && !mentioned {
+                debug!("Ignoring the group message not addressed to the bot.");
+                return Ok(());
+            }
+            let msg = BotMessages::from(message);
+            id = msg.get_chat_id();
+            let (user_id, user_name) = msg.get_user();
+            let command;
+            let argument;
+
+            let text = msg.get_message();
+            // "@botname /cmd" is handled as "/cmd"
+            let mention = format!("@{}", self.bot_name());
+            let is_name_end = |rest: &str| {
+                rest.chars()
+                    .next()
+                    .is_none_or(|c| !c.is_alphanumeric() && c != '_')
+            };
+            let text = match text.get(..mention.len()) {
+                Some(start)
+                    if start.eq_ignore_ascii_case(&mention)
+                        && is_name_end(&text[mention.len()..]) =>
+                {
+                    text[mention.len()..].trim_start().to_string()
+                }
+                _ => text,
+            };
+            if text.is_empty() {
+                debug!("Ignoring message without text from {}.", user_name);
+                return Ok(());
+            }
+
+            match self.access.approval(user_id) {
+                Some(Approval::Approved) => {}
+                Some(Approval::Denied) => {
+                    debug!("Ignoring the message of the denied user {}.", user_name);
+                    return Ok(());
+                }
+                // the group members are not necessarily talking to the bot.
+                _ if !private && !mentioned && !self.is_command(&text) => {
+                    debug!("Ignoring the group message of the unapproved user {user_name}.");
+                    return Ok(());
+                }
+                _ => {
+                    self.request_approval(user_id, &user_name).await?;
+                    let waiting = "Your access is waiting for the owner's approval.";
+                    return self
+                        .reply(&id.to_string(), waiting.into(), None)
+                        .await
+                        .map(|_| ());
+                }
+            }
+
+            let user = self.get_user(id, user_id, &user_name).await;
+
+            // if we are in chat mode, interpret the message as llm ask request
+            if user.is_in_chat_mode().await && !text.starts_with(B::chat_exit_command().unwrap()) {
+                command = B::llm_request_command();
+                argument = text;
+            } else {
+                let mut message = text.split_whitespace();
+                command = match message.next().map(|cmd| self.strip_mention(cmd)) {
+                    Some(None) => {
+                        debug!("Ignoring the command addressed to another bot.");
+                        return Ok(());
+                    }
+                    cmd => cmd.flatten(),
+                };
+                argument = message.collect::<Vec<&str>>().join(" ");
+            }
+            debug!("Cmd: {:?}, Arg: {:?}", command, argument);
+
+            answer = if let Some((cmd, bot_command)) =
+                command.and_then(|cmd| self.command_list.get_key_value(cmd))
+            {
+                if self
+                    .access
+                    .authorize(user_id, &user_name, cmd, bot_command.allowed_roles())
+                {
+                    bot_command.handle(user.clone(), argument).await
+                } else {
+                    format!("You are not allowed to use {cmd}.").into()
+                }
+            } else if private {
+                "Did not understand!".into()
+            } else {
+                // the group members are not necessarily talking to the bot.
+                BotReply::NoReply
+            };
+        } else {
+            info!(
+                "Skipping unsupported update {} ({}).",
+                update.update_id,
+                update.kind()
+            );
+            return Ok(());
+        }
+        let chat_id = id.to_string();
+        let previous = if edited {
+            self.replies
+                .lock()
+                .expect("poisoned lock")
+                .get(id, message_id)
+        } else {
+            None
+        };
+        let sent = match previous {
+            Some(previous) => self.replace_reply(&chat_id, previous, answer).await,
+            None => self.reply(&chat_id, answer, None).await,
+        };
+        match sent {
+            Result::Ok(reply_ids) => {
+                self.replies
+                    .lock()
+                    .expect("poisoned lock")
+                    .insert(id, message_id, reply_ids);
+            }
+            Err(e) => {
+                // let the user know, instead of leaving them without an answer.
+                self.reply(&chat_id, "Sorry, I could not send the answer!".into(), None)
+                    .await?;
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the webhook, the certificate is only needed if it is self-signed.
+    async fn set_webhook(&self, webhook_url: &str, certificate: Option<Vec<u8>>) -> Result<()> {
+        let url = self.method_url("setWebhook");
+
+        // the configured secret, or a new one with every certificate.
+        let secret: String = self.config.webhook_secret.clone().unwrap_or_else(|| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(Self::SECRET_LEN)
+                .map(char::from)
+                .collect()
+        });
+        let mut form = multipart::Form::new()
+            .text("url", webhook_url.to_string())
+            .text(
+                "allowed_updates",
+                serde_json::to_string(&Self::ALLOWED_UPDATES)?,
+            )
+            // the updates queued while the webhook is changed are still delivered.
+            .text("drop_pending_updates", serde_json::to_string(&false)?)
+            .text("secret_token", secret.clone());
+        if let Some(certificate) = certificate {
+            form = form.part(
+                "certificate",
+                Part::bytes(certificate).file_name("cert.pem"),
+            );
+        }
+
+        // telegram may use the new secret before answering, the previous one is
+        // only given back if the webhook is not changed.
+        let previous = self
+            .secret
+            .write()
+            .expect("poisoned lock")
+            .replace(secret.clone());
+        let result = match self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "multipart/form-data")
+            .multipart(form)
+            .send()
+            .await
+            .context("Could not set the webhook")
+        {
+            Result::Ok(resp) => Self::api_result("setWebhook", resp).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Result::Ok(result) => debug!("[webhook set]{:#?}", result),
+            Err(e) => {
+                *self.secret.write().expect("poisoned lock") = previous;
+                return Err(e);
+            }
+        }
+        if self.config.webhook_secret.is_none() {
+            if let Err(e) = write_secret(&self.secret_file(), secret.as_bytes()).await {
+                error!("Could not save the webhook secret: {:?}", e);
+            }
+        }
         Ok(())
     }
 
+    fn secret_file(&self) -> PathBuf {
+        self.config
+            .webhook_secret_file
+            .clone()
+            .unwrap_or_else(|| Self::SECRET_FILE.into())
+    }
+
     async fn set_my_commands(&self, commands: Vec<&str>) -> Result<()> {
         let cmds = commands
             .iter()
@@ -60,10 +737,7 @@ impl<B: BotCommands> TelegramBot<B> {
             commands: cmds,
             metadata: BotCommandsParams::default(),
         };
-        let url = format!(
-            "https://api.telegram.org/bot{}/setMyCommands",
-            self.config.token
-        );
+        let url = self.method_url("setMyCommands");
 
         let to_send = serde_json::to_string(&payload)?;
         self.client
@@ -81,129 +755,182 @@ impl<B: BotCommands> TelegramBot<B> {
 #[async_trait]
 impl<B: BotCommands + 'static> Bot for TelegramBot<B> {
     fn new(config: BotConfig) -> Self {
+        let access = AccessControl::new(&config);
+        // the secret of the webhook set by a previous run, unless one is configured.
+        let secret = config.webhook_secret.clone().or_else(|| {
+            let file = config
+                .webhook_secret_file
+                .clone()
+                .unwrap_or_else(|| Self::SECRET_FILE.into());
+            let secret = std::fs::read_to_string(file).ok()?;
+            Some(secret.trim().to_string()).filter(|secret| !secret.is_empty())
+        });
+        let secret = RwLock::new(secret);
+        let updates = UpdateTracker::new(Self::TRACKED_UPDATES, config.update_id_file.clone());
+        let dispatcher = Dispatcher::new(
+            config
+                .max_concurrent_handlers
+                .unwrap_or(Self::MAX_CONCURRENT_HANDLERS),
+        );
         TelegramBot {
             client: reqwest::Client::new(),
             config,
             users: Arc::new(Mutex::new(HashMap::new())),
             command_list: B::command_list(),
+            callback_list: B::callback_list(),
+            offset: AtomicU64::new(0),
+            replies: std::sync::Mutex::new(ReplyTracker::new(Self::TRACKED_REPLIES)),
+            updates: std::sync::Mutex::new(updates),
+            saving_update: Mutex::new(()),
+            access,
+            dispatcher,
+            secret,
             _commands: PhantomData,
         }
     }
     async fn handle_message(&self, msg: String) -> Result<()> {
-        let answer: String;
-        let id: u64;
-        let update: Update = msg.into();
+        let update: Update = msg.parse()?;
         debug!("Received {:#?}", update);
-        if let Some(message) = update.message {
-            let msg = BotMessages::from(message);
-            id = msg.get_chat_id();
-            let (user_id, user_name) = msg.get_user();
-            let command;
-            let argument;
-
-            let mut users = self.users.lock().await;
-            if users.get(&user_id).is_none() {
-                // add the user in the hashmap
-                debug!(
-                    "Adding the user (id = {}), (name = {}).",
-                    user_id, user_name
-                );
-                users.insert(user_id, Arc::new(RwLock::new(BotUser::new())));
-            };
-
-            let text = msg.get_message();
-            let mut user = Arc::clone(users.get_mut(&user_id).unwrap());
-
-            // update the user activity
-            user.set_last_activity(chrono::Utc::now()).await;
-
-            // if we are in chat mode, interpret the message as llm ask request
-            if user.is_in_chat_mode().await && !text.starts_with(B::chat_exit_command().unwrap()) {
-                command = B::llm_request_command();
-                argument = text;
-            } else {
-                let mut message = text.split_whitespace();
-                command = message.next();
-                argument = message.collect::<Vec<&str>>().join(" ");
+        if !self
+            .updates
+            .lock()
+            .expect("poisoned lock")
+            .is_new(update.update_id)
+        {
+            info!("Dropping the duplicate update {}.", update.update_id);
+            return Ok(());
+        }
+        let update_id = update.update_id;
+        let result = match update.chat_id() {
+            Some(chat_id) => {
+                self.dispatcher
+                    .run(chat_id, self.handle_update(update))
+                    .await
             }
-            debug!("Cmd: {:?}, Arg: {:?}", command, argument);
+            None => self.handle_update(update).await,
+        };
+        // a failed update is not handled again either, telegram won't send it again.
+        self.save_handled(update_id).await;
+        result
+    }
 
-            answer = if let Some(bot_command) = self.command_list.get(command.unwrap()) {
-                bot_command.handle(user.clone(), argument).await
-            } else {
-                "Did not understand!".into()
-            };
-        } else {
-            bail!("Unsupported message format!");
+    fn update_chat_id(&self, msg: &str) -> Option<i64> {
+        msg.parse::<Update>().ok()?.chat_id()
+    }
+
+    async fn is_webhook_configured(&self, url: &str) -> Result<bool> {
+        // a webhook set manually, without giving its secret to the bot.
+        if self.webhook_secret().is_none() {
+            debug!("No secret token is known for the webhook.");
+            return Ok(false);
         }
-        self.reply(id, &answer).await?;
-        Ok(())
+        // the url holds the ip, or the domain, along with the port and the path.
+        let state = self.get_webhook_status().await?.url == url;
+        debug!(" webhook configured == {state}");
+        Ok(state)
     }
 
-    async fn is_webhook_configured(&self, ip: &str) -> Result<bool> {
-        //gets the web hook info, we use to know if the ip address set in the certificate
-        //is correct or not.
-        let url = format!(
-            "https://api.telegram.org/bot{}/getWebhookInfo",
-            self.config.token
-        );
-        let resp: Response<Webhook> = self.client.get(url).send().await?.text().await?.into();
-        if resp.ok {
-            if let Some(ip_addr) = resp.result.ip_address {
-                let state = ip_addr == ip && resp.result.has_custom_certificate;
-                debug!(" webhook configured == {state}");
-                return Ok(state);
-            }
+    async fn get_webhook_status(&self) -> Result<WebhookStatus> {
+        let url = self.method_url("getWebhookInfo");
+        let body = self.client.get(url).send().await?.text().await?;
+        let resp: Response<Webhook> = serde_json::from_str(&body)
+            .with_context(|| format!("Unexpected getWebhookInfo response: {body}"))?;
+        if !resp.ok {
+            bail!("getWebhookInfo failed: {body}");
         }
-        bail!("Could not get correct webhook");
+        let webhook = resp.result;
+        Ok(WebhookStatus {
+            url: webhook.url,
+            pending_updates: webhook.pending_update_count,
+            last_error: webhook
+                .last_error_date
+                .map(|date| (date, webhook.last_error_message.unwrap_or_default())),
+        })
     }
-    fn get_webhook_ips(&self) -> Result<Vec<&'static str>> {
-        // allow the telegram servers IP address
+
+    fn get_webhook_ips(&self) -> Result<Vec<IpNet>> {
         // According to https://core.telegram.org/bots/webhooks
-        // the allowed IP addresses would be 149.154.160.0/20 and 91.108.4.0/22
-        Ok(vec![
-            "91.108.4.*",
-            "91.108.5.*",
-            "91.108.6.*",
-            "91.108.7.*",
-            "149.154.16?.*",
-            "149.154.17?.*",
-        ])
-    }
-
-    async fn update_webhook_cert(&self, cert: PathBuf, ip: &str) -> Result<()> {
+        // the allowed IP addresses are 149.154.160.0/20 and 91.108.4.0/22
+        Ok(vec!["149.154.160.0/20".parse()?, "91.108.4.0/22".parse()?])
+    }
+
+    async fn update_webhook_cert(&self, cert: PathBuf, url: &str) -> Result<()> {
         // get the pubkey file
         let certificate = fs::read(&cert)
             .await
             .expect("Failed to read the certificate file");
+        self.set_webhook(url, Some(certificate)).await
+    }
 
-        let url = format!(
-            "https://api.telegram.org/bot{}/setWebhook",
-            self.config.token
-        );
+    async fn update_webhook(&self, url: &str) -> Result<()> {
+        self.set_webhook(url, None).await
+    }
 
-        let part = Part::bytes(certificate).file_name("cert.pem");
-        let form = multipart::Form::new()
-            .text("url", format!("https://{}", ip))
-            .part("certificate", part)
-            .text(
-                "allowed_updates",
-                serde_json::to_string(&vec!["message", "edited_message"])?,
-            )
-            .text("drop_pending_updates", serde_json::to_string(&true)?);
+    fn webhook_secret(&self) -> Option<String> {
+        self.secret.read().expect("poisoned lock").clone()
+    }
 
-        let resp = self
+    async fn delete_webhook(&self) -> Result<()> {
+        let url = self.method_url("deleteWebhook");
+        // keep the pending updates, they will be fetched by the polling loop.
+        let body = self
             .client
             .post(url)
-            .header(CONTENT_TYPE, "multipart/form-data")
-            .multipart(form)
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({"drop_pending_updates": false}).to_string())
             .send()
             .await
-            .context("Could not set the webhook")?;
-        debug!("[webhook set]{:#?}", resp.text().await);
+            .context("Could not delete the webhook")?
+            .text()
+            .await?;
+        let resp: Response<bool> = serde_json::from_str(&body)
+            .with_context(|| format!("Unexpected deleteWebhook response: {body}"))?;
+        if !resp.ok || !resp.result {
+            bail!("Telegram refused to delete the webhook: {body}");
+        }
+        debug!("Webhook deleted");
         Ok(())
     }
 
+    async fn poll_updates(&self) -> Result<Vec<String>> {
+        let url = self.method_url("getUpdates");
+        let body = self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(
+                json!({
+                    "offset": self.offset.load(Ordering::Relaxed),
+                    "timeout": Self::POLL_TIMEOUT,
+                    "allowed_updates": Self::ALLOWED_UPDATES,
+                })
+                .to_string(),
+            )
+            .send()
+            .await
+            .context("Could not get the updates")?
+            .text()
+            .await?;
+        let resp: Response<Vec<serde_json::Value>> = serde_json::from_str(&body)
+            .with_context(|| format!("Unexpected getUpdates response: {body}"))?;
+        if !resp.ok {
+            bail!("getUpdates failed: {body}");
+        }
+
+        let mut updates = Vec::new();
+        for update in resp.result {
+            let Some(update_id) = update["update_id"].as_u64() else {
+                error!("Received an update without id: {update}");
+                continue;
+            };
+            // acknowledge the update even if handling it fails, otherwise
+            // we would get it again in the next batch.
+            self.offset.store(update_id + 1, Ordering::Relaxed);
+            updates.push(update.to_string());
+        }
+        Ok(updates)
+    }
+
     async fn initialize(&self) -> Result<()> {
         let list = B::command_list();
         let commands: Vec<&str> = list.keys().map(|x| x.as_str()).collect();
@@ -213,26 +940,473 @@ impl<B: BotCommands + 'static> Bot for TelegramBot<B> {
     }
 
     async fn send_message(&self, dest: &str, msg: &str) -> Result<()> {
-        let payload = SendMessage {
-            chat_id: dest.to_string(),
-            text: msg.to_string(),
+        self.reply(dest, BotReply::text(msg), None)
+            .await
+            .context("could not send the message")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::BotCommandHandler;
+    use httpmock::MockServer;
+
+    #[derive(Default)]
+    struct TestCommands;
+
+    /// Edits the message of the button with its argument.
+    struct Echo;
+
+    #[async_trait]
+    impl BotCommandHandler for Echo {
+        async fn handle(&self, _user: SharedUser, args: String) -> BotReply {
+            BotReply::edit(format!("echo {args}"))
+        }
+    }
+
+    /// Waits for another user to meet, both answers are only sent together.
+    struct Meet;
+
+    static MEETING: std::sync::LazyLock<tokio::sync::Barrier> =
+        std::sync::LazyLock::new(|| tokio::sync::Barrier::new(2));
+
+    #[async_trait]
+    impl BotCommandHandler for Meet {
+        async fn handle(&self, _user: SharedUser, _args: String) -> BotReply {
+            MEETING.wait().await;
+            "met".into()
+        }
+    }
+
+    /// Echoes for the owner only.
+    struct OwnerEcho;
+
+    #[async_trait]
+    impl BotCommandHandler for OwnerEcho {
+        async fn handle(&self, user: SharedUser, args: String) -> BotReply {
+            Echo.handle(user, args).await
+        }
+        fn allowed_roles(&self) -> &'static [&'static str] {
+            &[OWNER_ROLE]
+        }
+    }
+
+    impl BotCommands for TestCommands {
+        fn command_list() -> CommandHashMap {
+            let mut commands = CommandHashMap::new();
+            commands.insert("/meet".to_string(), Box::new(Meet));
+            commands
+        }
+        fn callback_list() -> CommandHashMap {
+            let mut callbacks = CommandHashMap::new();
+            callbacks.insert("echo".to_string(), Box::new(Echo));
+            callbacks.insert("owner".to_string(), Box::new(OwnerEcho));
+            callbacks
+        }
+        fn chat_start_command() -> Option<&'static str> {
+            None
+        }
+        fn chat_exit_command() -> Option<&'static str> {
+            None
+        }
+        fn llm_request_command() -> Option<&'static str> {
+            None
+        }
+    }
+
+    /// A bot calling the mocked api, with the owner "1".
+    fn test_bot(server: &MockServer, config: BotConfig) -> TelegramBot<TestCommands> {
+        TelegramBot::new(BotConfig {
+            token: "token".to_string(),
+            chat_id: "1".to_string(),
+            api_url: Some(server.base_url()),
+            ..config
+        })
+    }
+
+    #[tokio::test]
+    async fn test_polling_offset() {
+        let server = MockServer::start();
+        let first = server.mock(|when, then| {
+            when.path("/bottoken/getUpdates")
+                .json_body_partial(r#"{"offset": 0}"#);
+            then.status(200).body(
+                r#"{"ok": true, "result": [{"update_id": 7, "poll": {}}, {"update_id": 8, "poll": {}}]}"#,
+            );
+        });
+        let next = server.mock(|when, then| {
+            when.path("/bottoken/getUpdates")
+                .json_body_partial(r#"{"offset": 9}"#);
+            then.status(200)
+                .body(r#"{"ok": false, "description": "Conflict"}"#);
+        });
+        let bot = test_bot(&server, BotConfig::default());
+
+        let updates = bot.poll_updates().await.unwrap();
+        assert_eq!(updates.len(), 2);
+        first.assert();
+        // the batch is acknowledged, even when the call fails the offset is kept.
+        assert!(bot.poll_updates().await.is_err());
+        assert!(bot.poll_updates().await.is_err());
+        next.assert_hits(2);
+    }
+
+    /// A text message of the user `from` in the chat `chat_id`.
+    fn text_update(update_id: u64, from: u64, chat_id: i64, text: &str) -> String {
+        let chat_type = if chat_id < 0 { "group" } else { "private" };
+        json!({
+            "update_id": update_id,
+            "message": {
+                "message_id": update_id,
+                "from": {"id": from, "is_bot": false, "first_name": "Mo"},
+                "chat": {"id": chat_id, "type": chat_type},
+                "date": 1700000000,
+                "text": text,
+            }
+        })
+        .to_string()
+    }
+
+    fn send_message_to<'a>(server: &'a MockServer, chat_id: &str, ok: bool) -> httpmock::Mock<'a> {
+        let chat_id = json!({ "chat_id": chat_id }).to_string();
+        server.mock(|when, then| {
+            when.path("/bottoken/sendMessage")
+                .json_body_partial(chat_id);
+            then.status(200)
+                .body(json!({"ok": ok, "result": {"message_id": 100}}).to_string());
+        })
+    }
+
+    #[tokio::test]
+    async fn test_approval_request() {
+        let server = MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let config = BotConfig {
+            approvals_file: Some(dir.path().join("approvals.json")),
             ..Default::default()
         };
+        let bot = test_bot(&server, config.clone());
+        let waiting = send_message_to(&server, "42", true);
+
+        // the owner can't be reached, and is asked again with the next message.
+        let mut owner = send_message_to(&server, "1", false);
+        assert!(bot
+            .handle_message(text_update(1, 42, 42, "hi"))
+            .await
+            .is_err());
+        assert_eq!(bot.access.approval(42), None);
+        owner.delete();
+        let owner = send_message_to(&server, "1", true);
+        bot.handle_message(text_update(2, 42, 42, "hi"))
+            .await
+            .unwrap();
+        assert_eq!(bot.access.approval(42), Some(Approval::Pending));
+        owner.assert();
+        waiting.assert();
+
+        // the chat of a group is not for the bot.
+        let group = send_message_to(&server, "-100", true);
+        bot.handle_message(text_update(3, 43, -100, "hello everyone"))
+            .await
+            .unwrap();
+        assert_eq!(bot.access.approval(43), None);
+        bot.handle_message(text_update(4, 43, -100, "/ip"))
+            .await
+            .unwrap();
+        assert_eq!(bot.access.approval(43), Some(Approval::Pending));
+        owner.assert_hits(2);
+        group.assert();
+
+        // the owner is not asked again after a restart.
+        let bot = test_bot(&server, config);
+        assert_eq!(bot.access.approval(42), Some(Approval::Pending));
+    }
+
+    /// A button pressed by the user `from`, on the message `message_id` of its
+    /// private chat if there is one.
+    fn callback_update(update_id: u64, from: u64, message_id: Option<u64>, data: &str) -> String {
+        let mut query = json!({
+            "id": format!("query{update_id}"),
+            "from": {"id": from, "is_bot": false, "first_name": "Mo"},
+            "data": data,
+        });
+        if let Some(message_id) = message_id {
+            query["message"] = json!({
+                "message_id": message_id,
+                "chat": {"id": from, "type": "private"},
+                "date": 1700000000,
+                "text": "pick one",
+            });
+        }
+        json!({"update_id": update_id, "callback_query": query}).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_callback_routing() {
+        let server = MockServer::start();
+        let bot = test_bot(&server, BotConfig::default());
+        // the buttons work even when their loading animation can't be stopped.
+        let answer = server.mock(|when, then| {
+            when.path("/bottoken/answerCallbackQuery");
+            then.status(500);
+        });
+        let edit = server.mock(|when, then| {
+            when.path("/bottoken/editMessageText")
+                .json_body_partial(r#"{"chat_id": "1", "message_id": 5, "text": "echo hi"}"#);
+            then.status(200)
+                .body(r#"{"ok": true, "result": {"message_id": 5}}"#);
+        });
+        let send = server.mock(|when, then| {
+            when.path("/bottoken/sendMessage")
+                .json_body_partial(r#"{"chat_id": "1", "text": "echo bye"}"#);
+            then.status(200)
+                .body(r#"{"ok": true, "result": {"message_id": 6}}"#);
+        });
+
+        bot.handle_message(callback_update(1, 1, Some(5), "echo:hi"))
+            .await
+            .unwrap();
+        edit.assert();
+        // the message of the button is gone, the answer is sent instead.
+        bot.handle_message(callback_update(2, 1, None, "echo:bye"))
+            .await
+            .unwrap();
+        send.assert();
+        bot.handle_message(callback_update(3, 1, Some(5), "unknown:hi"))
+            .await
+            .unwrap();
+        answer.assert_hits(3);
+        edit.assert_hits(1);
+        send.assert_hits(1);
+    }
 
-        let url = format!(
-            "https://api.telegram.org/bot{}/sendMessage",
-            self.config.token
+    #[tokio::test]
+    async fn test_callback_refused() {
+        let server = MockServer::start();
+        let bot = test_bot(
+            &server,
+            BotConfig {
+                roles: HashMap::from([("family".to_string(), vec![2])]),
+                ..Default::default()
+            },
         );
+        let refused = server.mock(|when, then| {
+            when.path("/bottoken/answerCallbackQuery")
+                .json_body_partial(r#"{"text": "You are not allowed to do this."}"#);
+            then.status(200).body(r#"{"ok": true, "result": true}"#);
+        });
+        let edit = server.mock(|when, then| {
+            when.path("/bottoken/editMessageText");
+            then.status(200)
+                .body(r#"{"ok": true, "result": {"message_id": 5}}"#);
+        });
 
-        let to_send = serde_json::to_string(&payload)?;
-        self.client
-            .post(url)
-            .header(CONTENT_TYPE, "application/json")
-            .body(to_send)
-            .send()
+        // a member without the role, a stranger, and the approval of a request.
+        bot.handle_message(callback_update(1, 2, Some(5), "owner:hi"))
             .await
-            .context("could not send the message")?;
+            .unwrap();
+        bot.handle_message(callback_update(2, 3, Some(5), "echo:hi"))
+            .await
+            .unwrap();
+        bot.handle_message(callback_update(3, 2, Some(5), "approval:approve:3"))
+            .await
+            .unwrap();
+        refused.assert_hits(3);
+        edit.assert_hits(0);
+        assert_eq!(bot.access.approval(3), None);
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn test_users_in_parallel() {
+        let server = MockServer::start();
+        let bot = test_bot(
+            &server,
+            BotConfig {
+                roles: HashMap::from([("family".to_string(), vec![2])]),
+                ..Default::default()
+            },
+        );
+        let sent = server.mock(|when, then| {
+            when.path("/bottoken/sendMessage")
+                .json_body_partial(r#"{"text": "met"}"#);
+            then.status(200)
+                .body(r#"{"ok": true, "result": {"message_id": 100}}"#);
+        });
+
+        // each user is answered only once the other one is being served.
+        tokio::time::timeout(
+            std::time::Duration::from_secs(5),
+            futures::future::try_join(
+                bot.handle_message(text_update(1, 1, 1, "/meet")),
+                bot.handle_message(text_update(2, 2, 2, "/meet")),
+            ),
+        )
+        .await
+        .expect("the users were not served in parallel")
+        .unwrap();
+        sent.assert_hits(2);
+    }
+
+    #[tokio::test]
+    async fn test_edited_message() {
+        let server = MockServer::start();
+        let bot = test_bot(&server, BotConfig::default());
+        let send = send_message_to(&server, "1", true);
+        let edit = server.mock(|when, then| {
+            when.path("/bottoken/editMessageText")
+                .json_body_partial(r#"{"chat_id": "1", "message_id": 100}"#);
+            then.status(200)
+                .body(r#"{"ok": true, "result": {"message_id": 100}}"#);
+        });
+        let delete = server.mock(|when, then| {
+            when.path("/bottoken/deleteMessage");
+            then.status(200).body(r#"{"ok": true, "result": true}"#);
+        });
+
+        bot.handle_message(text_update(10, 1, 1, "helo"))
+            .await
+            .unwrap();
+        send.assert();
+        // the answer of the edited message replaces the previous one.
+        let mut update: serde_json::Value =
+            serde_json::from_str(&text_update(11, 1, 1, "hello")).unwrap();
+        let mut message = update.as_object_mut().unwrap().remove("message").unwrap();
+        message["message_id"] = 10.into();
+        update["edited_message"] = message;
+        bot.handle_message(update.to_string()).await.unwrap();
+        edit.assert();
+        send.assert_hits(1);
+        delete.assert_hits(0);
+        assert_eq!(bot.replies.lock().unwrap().get(1, 10), Some(vec![100]));
+    }
+
+    #[test]
+    fn test_mentions() {
+        let bot = TelegramBot::<TestCommands>::new(BotConfig {
+            name: "@SuperBot".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(bot.strip_mention("/temp@superbot"), Some("/temp"));
+        assert_eq!(bot.strip_mention("/temp"), Some("/temp"));
+        assert_eq!(bot.strip_mention("/temp@otherbot"), None);
+        assert_eq!(bot.strip_mention("hello@superbot"), Some("hello@superbot"));
+
+        let message = |text: &str, replied_to: Option<&str>| -> Message {
+            // the mentions of the text, as found by telegram.
+            let entities: Vec<_> = text
+                .match_indices('@')
+                .map(|(offset, _)| {
+                    let length = text[offset + 1..]
+                        .find(|c: char| !c.is_alphanumeric() && c != '_')
+                        .map_or(text.len() - offset, |end| end + 1);
+                    json!({"type": "mention", "offset": offset, "length": length})
+                })
+                .collect();
+            let mut message = json!({
+                "message_id": 1,
+                "from": {"id": 2, "is_bot": false, "first_name": "Mo"},
+                "chat": {"id": -100, "type": "group"},
+                "date": 1700000000,
+                "text": text,
+                "entities": entities,
+            });
+            if let Some(author) = replied_to {
+                message["reply_to_message"] = json!({
+                    "message_id": 0,
+                    "from": {"id": 3, "is_bot": true, "first_name": "Bot", "username": author},
+                    "chat": {"id": -100, "type": "group"},
+                    "date": 1700000000,
+                    "text": "earlier",
+                });
+            }
+            serde_json::from_value(message).unwrap()
+        };
+        assert!(bot.is_addressed(&message("hey @SUPERBOT, the temp?", None)));
+        assert!(bot.is_addressed(&message("and now?", Some("superbot"))));
+        assert!(!bot.is_addressed(&message("and now?", Some("otherbot"))));
+        assert!(!bot.is_addressed(&message("hello everyone", None)));
+        assert!(!bot.is_addressed(&message("ask @superbot2 or @superbot_fan", None)));
+        // not a mention for telegram, e.g in an email address.
+        let mut email = message("me@superbot.org", None);
+        email.entities = None;
+        assert!(!bot.is_addressed(&email));
+    }
+
+    #[test]
+    fn test_saved_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("webhook_secret");
+        let config = BotConfig {
+            webhook_secret_file: Some(file.clone()),
+            ..Default::default()
+        };
+        let bot = TelegramBot::<TestCommands>::new(config.clone());
+        assert_eq!(bot.webhook_secret(), None);
+
+        // the secret of the previous run.
+        std::fs::write(&file, "s3cret\n").unwrap();
+        let bot = TelegramBot::<TestCommands>::new(config.clone());
+        assert_eq!(bot.webhook_secret().as_deref(), Some("s3cret"));
+        // the configured one wins.
+        let config = BotConfig {
+            webhook_secret: Some("configured".to_string()),
+            ..config
+        };
+        let bot = TelegramBot::<TestCommands>::new(config);
+        assert_eq!(bot.webhook_secret().as_deref(), Some("configured"));
+    }
+
+    #[tokio::test]
+    async fn test_webhook_secret() {
+        let server = MockServer::start();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("webhook_secret");
+        let config = BotConfig {
+            webhook_secret_file: Some(file.clone()),
+            ..Default::default()
+        };
+
+        // the webhook is not changed, the previous secret is still the right one.
+        let mut refused = server.mock(|when, then| {
+            when.path("/bottoken/setWebhook");
+            then.status(200)
+                .body(r#"{"ok": false, "description": "Bad Request"}"#);
+        });
+        std::fs::write(&file, "previous").unwrap();
+        let bot = test_bot(&server, config.clone());
+        assert!(bot.update_webhook("https://192.0.2.1/").await.is_err());
+        assert_eq!(bot.webhook_secret().as_deref(), Some("previous"));
+        refused.delete();
+
+        // a new secret is generated and kept for the next run.
+        let mut set = server.mock(|when, then| {
+            when.path("/bottoken/setWebhook");
+            then.status(200).body(r#"{"ok": true, "result": true}"#);
+        });
+        bot.update_webhook("https://192.0.2.1/").await.unwrap();
+        let secret = bot.webhook_secret().unwrap();
+        assert_ne!(secret, "previous");
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), secret);
+        set.assert();
+        set.delete();
+
+        // the configured secret is sent instead.
+        let configured = server.mock(|when, then| {
+            when.path("/bottoken/setWebhook")
+                .body_contains("configured");
+            then.status(200).body(r#"{"ok": true, "result": true}"#);
+        });
+        let bot = test_bot(
+            &server,
+            BotConfig {
+                webhook_secret: Some("configured".to_string()),
+                ..config
+            },
+        );
+        bot.update_webhook("https://192.0.2.1/").await.unwrap();
+        assert_eq!(bot.webhook_secret().as_deref(), Some("configured"));
+        configured.assert();
     }
 }
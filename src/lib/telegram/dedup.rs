@@ -0,0 +1,124 @@
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::path::PathBuf;
+
+use tracing::error;
+
+/// Remembers the recently handled update ids, to drop the updates that telegram
+/// delivers again (e.g after a slow answer or a restart of the server).
+pub struct UpdateTracker {
+    capacity: usize,
+    seen: HashSet<u64>,
+    // arrival order, to forget the oldest ids first.
+    order: VecDeque<u64>,
+    // received, but not handled yet.
+    in_flight: BTreeSet<u64>,
+    highest_handled: Option<u64>,
+    // the ids up to it were all handled, kept in `file` to survive restarts.
+    mark: Option<u64>,
+    // the mark of the previous run, the updates up to it were handled before.
+    restored: Option<u64>,
+    file: Option<PathBuf>,
+}
+
+impl UpdateTracker {
+    pub fn new(capacity: usize, file: Option<PathBuf>) -> Self {
+        let mark = file.as_ref().and_then(|file| {
+            let content = std::fs::read_to_string(file).ok()?;
+            content
+                .trim()
+                .parse()
+                .map_err(|e| error!("Invalid update id in {}: {e}", file.display()))
+                .ok()
+        });
+        Self {
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            in_flight: BTreeSet::new(),
+            highest_handled: None,
+            mark,
+            restored: mark,
+            file,
+        }
+    }
+
+    /// Records the update, returns false if it was already received.
+    pub fn is_new(&mut self, update_id: u64) -> bool {
+        if self.seen.contains(&update_id) {
+            return false;
+        }
+        // the ids are only sequential for a while (telegram picks a random one after
+        // a week without updates), so only the close ones are compared to the mark.
+        if let Some(restored) = self.restored {
+            if update_id <= restored && restored - update_id < self.capacity as u64 {
+                return false;
+            }
+        }
+
+        self.seen.insert(update_id);
+        self.in_flight.insert(update_id);
+        self.order.push_back(update_id);
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// Records that the update was handled, returns the file and the mark to save
+    /// in it when the mark moved. The updates of other chats can be handled in any
+    /// order, so the mark stays below the ones still being handled.
+    pub fn handled(&mut self, update_id: u64) -> Option<(PathBuf, u64)> {
+        self.in_flight.remove(&update_id);
+        self.highest_handled = self.highest_handled.max(Some(update_id));
+        let mark = match self.in_flight.first() {
+            Some(lowest) => self.highest_handled.min(lowest.checked_sub(1)),
+            None => self.highest_handled,
+        };
+        if mark.is_none() || mark == self.mark {
+            return None;
+        }
+        self.mark = mark;
+        Some((self.file.clone()?, mark?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duplicates() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("update_id");
+        let mut tracker = UpdateTracker::new(10, Some(file.clone()));
+        let handle = |tracker: &mut UpdateTracker, update_id| {
+            if let Some((file, mark)) = tracker.handled(update_id) {
+                std::fs::write(file, mark.to_string()).unwrap();
+            }
+        };
+
+        assert!(tracker.is_new(100));
+        handle(&mut tracker, 100);
+        assert!(tracker.is_new(102));
+        handle(&mut tracker, 102);
+        assert!(!tracker.is_new(100));
+        // delivered late, but never handled.
+        assert!(tracker.is_new(101));
+        assert!(!tracker.is_new(101));
+        assert_eq!(tracker.handled(101), None);
+        // 104 is handled while 103 is still being handled, when the server stops.
+        assert!(tracker.is_new(103));
+        assert!(tracker.is_new(104));
+        handle(&mut tracker, 104);
+
+        // after a restart, only the mark is known.
+        let mut tracker = UpdateTracker::new(10, Some(file));
+        assert!(!tracker.is_new(102));
+        assert!(!tracker.is_new(95));
+        assert!(tracker.is_new(103));
+        // a random id far from the mark.
+        assert!(tracker.is_new(7));
+    }
+}
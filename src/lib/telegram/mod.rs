@@ -1,2 +1,4 @@
 pub mod bot;
+pub mod dedup;
+pub mod replies;
 pub mod types;
@@ -0,0 +1,58 @@
+use std::collections::{HashMap, VecDeque};
+
+/// Remembers which bot messages answered which user message, so that the
+/// answer can be updated when the user edits their message.
+pub struct ReplyTracker {
+    capacity: usize,
+    replies: HashMap<(i64, u64), Vec<u64>>,
+    // insertion order, to forget the oldest answers first.
+    order: VecDeque<(i64, u64)>,
+}
+
+impl ReplyTracker {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            replies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn insert(&mut self, chat_id: i64, message_id: u64, reply_ids: Vec<u64>) {
+        let key = (chat_id, message_id);
+        if self.replies.insert(key, reply_ids).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.replies.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn get(&self, chat_id: i64, message_id: u64) -> Option<Vec<u64>> {
+        self.replies.get(&(chat_id, message_id)).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replies() {
+        let mut tracker = ReplyTracker::new(2);
+        tracker.insert(1, 10, vec![100, 101]);
+        tracker.insert(-5, 10, vec![102]);
+        assert_eq!(tracker.get(1, 10), Some(vec![100, 101]));
+        assert_eq!(tracker.get(-5, 10), Some(vec![102]));
+
+        // answered again after an edit, the message keeps its age.
+        tracker.insert(1, 10, vec![103]);
+        assert_eq!(tracker.get(1, 10), Some(vec![103]));
+        tracker.insert(1, 11, vec![104]);
+        assert_eq!(tracker.get(1, 10), None);
+        assert_eq!(tracker.get(-5, 10), Some(vec![102]));
+        assert_eq!(tracker.get(1, 11), Some(vec![104]));
+    }
+}
@@ -1,51 +1,121 @@
-use core::panic;
+use std::collections::HashMap;
+use std::str::FromStr;
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_with::TimestampSeconds;
-use tracing::{debug, info};
+use thiserror::Error;
+use tracing::debug;
 
-use crate::types::BotMessage;
+use crate::types::{BotMessage, Button, Keyboard, ParseMode};
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatType {
+    Private,
+    Group,
+    Supergroup,
+    Channel,
+}
 
 #[derive(Deserialize, Clone, Debug)]
 #[allow(dead_code)]
 pub struct Chat {
-    pub id: u64,
-    first_name: String,
-    #[serde(default)]
-    last_name: String,
-    #[serde(default)]
-    username: String,
+    pub id: i64,
+    #[serde(rename(deserialize = "type"))]
+    pub chat_type: ChatType,
+    title: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    username: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct MessageEntity {
     #[serde(rename(deserialize = "type"))]
-    chat_type: String,
+    pub entity_type: String,
+    pub offset: usize,
+    pub length: usize,
+    pub url: Option<String>,
+    pub user: Option<User>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct PhotoSize {
+    pub file_id: String,
+    pub file_unique_id: String,
+    pub width: u32,
+    pub height: u32,
+    pub file_size: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Document {
+    pub file_id: String,
+    pub file_unique_id: String,
+    pub file_name: Option<String>,
+    pub mime_type: Option<String>,
+    pub file_size: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Voice {
+    pub file_id: String,
+    pub file_unique_id: String,
+    pub duration: u32,
+    pub mime_type: Option<String>,
+    pub file_size: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub horizontal_accuracy: Option<f64>,
 }
 
 #[serde_with::serde_as]
 #[derive(Deserialize, Clone, Debug)]
 pub struct Message {
-    #[serde(alias = "message_id")]
-    _message_id: u64,
-    pub from: User,
+    pub message_id: u64,
+    /// Empty for messages sent to channels.
+    pub from: Option<User>,
     pub chat: Chat,
     #[serde_as(as = "TimestampSeconds<i64>")]
     #[serde(alias = "date")]
     _date: DateTime<Utc>,
-    pub text: String,
-    #[serde(skip)]
-    #[serde(alias = "entities")]
-    _entities: String,
+    pub text: Option<String>,
+    pub entities: Option<Vec<MessageEntity>>,
+    pub reply_to_message: Option<Box<Message>>,
+    pub photo: Option<Vec<PhotoSize>>,
+    pub document: Option<Document>,
+    pub voice: Option<Voice>,
+    pub location: Option<Location>,
+    /// Caption of the photo, document ..
+    pub caption: Option<String>,
 }
 
 impl BotMessage for Message {
     fn get_message(&self) -> String {
-        self.text.clone()
+        self.text
+            .as_ref()
+            .or(self.caption.as_ref())
+            .cloned()
+            .unwrap_or_default()
     }
 
     fn get_user(&self) -> (u64, String) {
-        (self.from.id, self.from.first_name.clone())
+        match &self.from {
+            Some(user) => (user.id, user.first_name.clone()),
+            // the sender of channel posts is the channel itself.
+            None => (
+                self.chat.id.unsigned_abs(),
+                self.chat.title.clone().unwrap_or_default(),
+            ),
+        }
     }
 
-    fn get_chat_id(&self) -> u64 {
+    fn get_chat_id(&self) -> i64 {
         self.chat.id
     }
 }
@@ -54,10 +124,10 @@ impl BotMessage for Message {
 #[allow(dead_code)]
 pub struct User {
     pub id: u64,
-    is_bot: bool,
+    pub is_bot: bool,
     pub first_name: String,
     last_name: Option<String>,
-    username: Option<String>,
+    pub username: Option<String>,
     language_code: Option<String>,
     is_premium: Option<bool>,
     added_to_attachment_menu: Option<bool>,
@@ -75,6 +145,15 @@ pub struct ChatMemberUpdated {
     _date: DateTime<Utc>,
 }
 
+#[derive(Deserialize, Clone, Debug)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: User,
+    /// The message holding the button, if it is not too old.
+    pub message: Option<Message>,
+    pub data: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Update {
     pub update_id: u64,
@@ -82,6 +161,50 @@ pub struct Update {
     pub edited_message: Option<Message>,
     pub channel_post: Option<Message>,
     pub edited_channel_post: Option<Message>,
+    pub callback_query: Option<CallbackQuery>,
+    /// The update kinds we don't handle (yet).
+    #[serde(flatten)]
+    pub other: HashMap<String, serde_json::Value>,
+}
+
+impl Update {
+    /// Name of the field holding the payload of the update, e.g "message".
+    pub fn kind(&self) -> &str {
+        if self.message.is_some() {
+            "message"
+        } else if self.edited_message.is_some() {
+            "edited_message"
+        } else if self.channel_post.is_some() {
+            "channel_post"
+        } else if self.edited_channel_post.is_some() {
+            "edited_channel_post"
+        } else if self.callback_query.is_some() {
+            "callback_query"
+        } else {
+            self.other.keys().next().map_or("unknown", |k| k.as_str())
+        }
+    }
+
+    /// The chat the update belongs to, if it is a supported one.
+    pub fn chat_id(&self) -> Option<i64> {
+        if let Some(message) = self.message.as_ref().or(self.edited_message.as_ref()) {
+            return Some(message.chat.id);
+        }
+        self.callback_query.as_ref().map(|query| {
+            query
+                .message
+                .as_ref()
+                .map_or(query.from.id as i64, |message| message.chat.id)
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum UpdateError {
+    #[error("the update is not valid json: {0}")]
+    InvalidJson(#[source] serde_json::Error),
+    #[error("the update has an unexpected format: {0}")]
+    InvalidFormat(#[source] serde_json::Error),
 }
 
 #[derive(Deserialize, Debug)]
@@ -90,16 +213,19 @@ pub struct Response<T> {
     pub result: T,
 }
 
+#[serde_with::serde_as]
 #[derive(Deserialize)]
 pub struct Webhook {
-    #[serde(alias = "url")]
-    _url: String,
+    pub url: String,
     pub has_custom_certificate: bool,
-    #[serde(alias = "pending_update_count")]
-    _pending_update_count: u32,
+    pub pending_update_count: u32,
     #[serde(alias = "max_connections")]
-    _max_connections: u32,
+    _max_connections: Option<u32>,
     pub ip_address: Option<String>,
+    #[serde_as(as = "Option<TimestampSeconds<i64>>")]
+    #[serde(default)]
+    pub last_error_date: Option<DateTime<Utc>>,
+    pub last_error_message: Option<String>,
 }
 
 impl<T: for<'a> Deserialize<'a>> From<String> for Response<T> {
@@ -108,15 +234,15 @@ impl<T: for<'a> Deserialize<'a>> From<String> for Response<T> {
     }
 }
 
-impl From<String> for Update {
-    fn from(value: String) -> Self {
-        if let Ok(update_str) = serde_json::from_str(&value) {
-            debug!("{:#?}", update_str);
-            update_str
-        } else {
-            info!("Invalid request state: {value}");
-            panic!()
-        }
+impl FromStr for Update {
+    type Err = UpdateError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let json: serde_json::Value =
+            serde_json::from_str(value).map_err(UpdateError::InvalidJson)?;
+        let update: Update = serde_json::from_value(json).map_err(UpdateError::InvalidFormat)?;
+        debug!("{:#?}", update);
+        Ok(update)
     }
 }
 
@@ -187,8 +313,8 @@ pub struct SendMessage {
     #[serde(skip)]
     pub _message_thread_id: Option<u64>,
     pub text: String,
-    #[serde(skip)]
-    pub _parse_mode: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<ParseMode>,
     #[serde(skip)]
     pub _entities: Option<String>,
     #[serde(skip)]
@@ -199,6 +325,128 @@ pub struct SendMessage {
     pub _protect_content: Option<String>,
     #[serde(skip)]
     pub _reply_parameters: Option<String>,
-    #[serde(skip)]
-    pub _reply_markup: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Serialize, Default)]
+pub struct EditMessageText {
+    pub chat_id: String,
+    pub message_id: u64,
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parse_mode: Option<ParseMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_markup: Option<InlineKeyboardMarkup>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InlineKeyboardButton {
+    pub text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback_data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InlineKeyboardMarkup {
+    pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
+}
+
+impl From<Keyboard> for InlineKeyboardMarkup {
+    fn from(keyboard: Keyboard) -> Self {
+        let inline_keyboard = keyboard
+            .rows
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|button| match button {
+                        Button::Callback { text, data } => InlineKeyboardButton {
+                            text,
+                            callback_data: Some(data),
+                            url: None,
+                        },
+                        Button::Url { text, url } => InlineKeyboardButton {
+                            text,
+                            callback_data: None,
+                            url: Some(url),
+                        },
+                    })
+                    .collect()
+            })
+            .collect();
+        Self { inline_keyboard }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: &str = r#"{"id": 42, "is_bot": false, "first_name": "Mo"}"#;
+    const CHAT: &str = r#"{"id": 42, "type": "private", "first_name": "Mo"}"#;
+
+    fn update_with(kind: &str, payload: &str) -> String {
+        format!(r#"{{"update_id": 7, "{kind}": {payload}}}"#)
+    }
+
+    #[test]
+    fn test_parse_text_message() {
+        let msg = format!(
+            r#"{{"message_id": 1, "from": {USER}, "chat": {CHAT}, "date": 1700000000,
+            "text": "/temp Berlin", "entities": [{{"type": "bot_command", "offset": 0, "length": 5}}]}}"#
+        );
+        let update: Update = update_with("message", &msg).parse().unwrap();
+        let message = update.message.unwrap();
+        assert_eq!(message.get_message(), "/temp Berlin");
+        assert_eq!(message.get_user(), (42, "Mo".to_string()));
+        assert_eq!(message.chat.chat_type, ChatType::Private);
+        assert_eq!(message.entities.unwrap()[0].entity_type, "bot_command");
+    }
+
+    #[test]
+    fn test_parse_non_text_messages() {
+        let photo = format!(
+            r#"{{"message_id": 2, "from": {USER}, "chat": {CHAT}, "date": 1700000000, "caption": "look",
+            "photo": [{{"file_id": "a", "file_unique_id": "b", "width": 90, "height": 90}}]}}"#
+        );
+        let update: Update = update_with("message", &photo).parse().unwrap();
+        let message = update.message.unwrap();
+        assert_eq!(message.photo.as_ref().unwrap().len(), 1);
+        assert_eq!(message.get_message(), "look");
+
+        let sticker = format!(
+            r#"{{"message_id": 3, "from": {USER}, "chat": {CHAT}, "date": 1700000000,
+            "sticker": {{"file_id": "c"}}}}"#
+        );
+        let update: Update = update_with("message", &sticker).parse().unwrap();
+        assert_eq!(update.message.unwrap().get_message(), "");
+
+        let location = format!(
+            r#"{{"message_id": 4, "from": {USER}, "chat": {CHAT}, "date": 1700000000,
+            "location": {{"latitude": 52.5, "longitude": 13.4}}}}"#
+        );
+        let update: Update = update_with("message", &location).parse().unwrap();
+        assert_eq!(update.message.unwrap().location.unwrap().latitude, 52.5);
+    }
+
+    #[test]
+    fn test_parse_unknown_update_kind() {
+        let update: Update = update_with("poll", r#"{"id": "1"}"#).parse().unwrap();
+        assert!(update.message.is_none());
+        assert_eq!(update.kind(), "poll");
+    }
+
+    #[test]
+    fn test_parse_invalid_update() {
+        assert!(matches!(
+            "not json".parse::<Update>(),
+            Err(UpdateError::InvalidJson(_))
+        ));
+        assert!(matches!(
+            r#"{"message": {}}"#.parse::<Update>(),
+            Err(UpdateError::InvalidFormat(_))
+        ));
+    }
 }
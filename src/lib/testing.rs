@@ -0,0 +1,101 @@
+//! Test doubles shared by the tests of the modules driving a `Bot`.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use ipnet::IpNet;
+
+use crate::types::WebhookStatus;
+use crate::{Bot, BotConfig, Config};
+
+/// Records the messages it sends, and reports the webhook status it is given.
+#[derive(Default)]
+pub struct FakeBot {
+    pub sent: Mutex<Vec<(String, String)>>,
+    pub webhook: Mutex<WebhookStatus>,
+    /// Makes `send_message` fail.
+    pub offline: AtomicBool,
+}
+
+impl FakeBot {
+    pub fn sent(&self) -> Vec<(String, String)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Bot for FakeBot {
+    async fn initialize(&self) -> Result<()> {
+        Ok(())
+    }
+    async fn send_message(&self, dest: &str, msg: &str) -> Result<()> {
+        if self.offline.load(Ordering::SeqCst) {
+            bail!("offline");
+        }
+        self.sent
+            .lock()
+            .unwrap()
+            .push((dest.to_string(), msg.to_string()));
+        Ok(())
+    }
+    async fn handle_message(&self, _msg: String) -> Result<()> {
+        Ok(())
+    }
+    async fn is_webhook_configured(&self, url: &str) -> Result<bool> {
+        Ok(self.webhook.lock().unwrap().url == url)
+    }
+    async fn get_webhook_status(&self) -> Result<WebhookStatus> {
+        Ok(self.webhook.lock().unwrap().clone())
+    }
+    async fn update_webhook_cert(&self, _cert: PathBuf, url: &str) -> Result<()> {
+        self.update_webhook(url).await
+    }
+    async fn update_webhook(&self, url: &str) -> Result<()> {
+        *self.webhook.lock().unwrap() = WebhookStatus {
+            url: url.to_string(),
+            ..Default::default()
+        };
+        Ok(())
+    }
+    fn webhook_secret(&self) -> Option<String> {
+        None
+    }
+    async fn delete_webhook(&self) -> Result<()> {
+        Ok(())
+    }
+    async fn poll_updates(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+    fn get_webhook_ips(&self) -> Result<Vec<IpNet>> {
+        Ok(vec![])
+    }
+    fn new(_config: BotConfig) -> Self {
+        Self::default()
+    }
+}
+
+/// A config with the owner chat "1", followed by the given toml.
+pub fn config(extra: &str) -> Config {
+    let config = format!(
+        r#"
+        [bot]
+        name = "testbot"
+        token = "token"
+        chat_id = "1"
+        db_token = ""
+        coinmarket_token = ""
+
+        [server]
+        ip = "127.0.0.1"
+        port = 8443
+        pubkey_path = "pub.pem"
+        privkey_path = "priv.pem"
+
+        {extra}
+        "#
+    );
+    toml::from_str(&config).unwrap()
+}
@@ -0,0 +1,179 @@
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{ClientHelloResponse, SslAcceptor, SslAcceptorBuilder, SslMethod};
+use openssl::x509::X509;
+use tracing::{error, info};
+
+use crate::utils::certificate_expiry;
+
+/// A certificate along with its private key.
+pub struct CertifiedKey {
+    pub cert: X509,
+    pub chain: Vec<X509>,
+    pub key: PKey<Private>,
+}
+
+/// Serves the current certificate to every new connection, so that a regenerated
+/// certificate is used without restarting the server.
+pub struct CertResolver {
+    pubkey_path: PathBuf,
+    privkey_path: PathBuf,
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn new(pubkey_path: impl Into<PathBuf>, privkey_path: impl Into<PathBuf>) -> Self {
+        Self {
+            pubkey_path: pubkey_path.into(),
+            privkey_path: privkey_path.into(),
+            current: RwLock::new(None),
+        }
+    }
+
+    fn load(&self) -> Result<CertifiedKey> {
+        let pem = std::fs::read(&self.pubkey_path)
+            .with_context(|| format!("Could not read {}", self.pubkey_path.display()))?;
+        let mut certs = X509::stack_from_pem(&pem)
+            .with_context(|| format!("Invalid certificate {}", self.pubkey_path.display()))?
+            .into_iter();
+        let Some(cert) = certs.next() else {
+            bail!("No certificate in {}", self.pubkey_path.display());
+        };
+        let pem = std::fs::read(&self.privkey_path)
+            .with_context(|| format!("Could not read {}", self.privkey_path.display()))?;
+        let key = PKey::private_key_from_pem(&pem)
+            .with_context(|| format!("Invalid private key {}", self.privkey_path.display()))?;
+        if !cert.public_key()?.public_eq(&key) {
+            bail!("The private key does not match the certificate");
+        }
+        Ok(CertifiedKey {
+            cert,
+            chain: certs.collect(),
+            key,
+        })
+    }
+
+    /// Checks that the key pair can be loaded, and returns the end of its validity.
+    pub fn check(&self) -> Result<DateTime<Utc>> {
+        self.load()?;
+        certificate_expiry(&self.pubkey_path)
+    }
+
+    /// Reads the key pair again, the connections already open keep the previous one.
+    pub fn reload(&self) -> Result<()> {
+        let certified = self.load()?;
+        *self.current.write().expect("poisoned lock") = Some(Arc::new(certified));
+        info!("Loaded the certificate {}.", self.pubkey_path.display());
+        Ok(())
+    }
+
+    /// The key pair in use, read from the files the first time.
+    pub fn current(&self) -> Result<Arc<CertifiedKey>> {
+        if let Some(current) = self.current.read().expect("poisoned lock").as_ref() {
+            return Ok(current.clone());
+        }
+        self.reload()?;
+        self.current()
+    }
+
+    /// The acceptor of the server, picking the certificate at each handshake.
+    pub fn acceptor(self: &Arc<Self>) -> Result<SslAcceptorBuilder> {
+        let current = self.current()?;
+        let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        builder.set_certificate(&current.cert)?;
+        builder.set_private_key(&current.key)?;
+        builder.check_private_key()?;
+        // the clients connecting to an ip send no SNI, hence the client hello
+        // callback instead of the servername one.
+        let resolver = self.clone();
+        builder.set_client_hello_callback(move |ssl, _alert| {
+            match resolver.current() {
+                Ok(current) => {
+                    ssl.set_certificate(&current.cert)?;
+                    ssl.set_private_key(&current.key)?;
+                    for cert in &current.chain {
+                        ssl.add_chain_cert(cert.clone())?;
+                    }
+                }
+                // keep serving the certificate of the acceptor.
+                Err(e) => error!("Could not load the certificate: {e:?}"),
+            }
+            Ok(ClientHelloResponse::SUCCESS)
+        });
+        Ok(builder)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::CertificateConfig;
+    use crate::utils::generate_certificate;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use std::net::{TcpListener, TcpStream};
+
+    /// The certificate served by the acceptor to a new connection.
+    fn served_certificate(acceptor: &SslAcceptor) -> X509 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let stream = TcpStream::connect(addr).unwrap();
+            let stream = connector
+                .build()
+                .configure()
+                .unwrap()
+                .verify_hostname(false)
+                .connect("localhost", stream)
+                .unwrap();
+            stream.ssl().peer_certificate().unwrap()
+        });
+        let (stream, _) = listener.accept().unwrap();
+        // the handshake is done as soon as the client has the certificate.
+        let _stream = acceptor.accept(stream);
+        client.join().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_hot_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let pubkey = dir.path().join("cert.pem");
+        let privkey = dir.path().join("key.pem");
+        generate_certificate(
+            pubkey.clone(),
+            privkey.clone(),
+            "1.1.1.1",
+            &CertificateConfig::default(),
+        )
+        .await
+        .unwrap();
+        let resolver = Arc::new(CertResolver::new(&pubkey, &privkey));
+        let acceptor = resolver.acceptor().unwrap().build();
+        let first = served_certificate(&acceptor);
+        assert_eq!(
+            first.to_der().unwrap(),
+            resolver.current().unwrap().cert.to_der().unwrap()
+        );
+
+        generate_certificate(pubkey, privkey, "2.2.2.2", &CertificateConfig::default())
+            .await
+            .unwrap();
+        // still served until reloaded.
+        assert_eq!(
+            served_certificate(&acceptor).to_der().unwrap(),
+            first.to_der().unwrap()
+        );
+        resolver.reload().unwrap();
+        let second = served_certificate(&acceptor);
+        assert_ne!(second.to_der().unwrap(), first.to_der().unwrap());
+        assert_eq!(
+            second.to_der().unwrap(),
+            resolver.current().unwrap().cert.to_der().unwrap()
+        );
+    }
+}
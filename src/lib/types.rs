@@ -12,15 +12,105 @@ use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use enum_dispatch::enum_dispatch;
+use ipnet::IpNet;
 use llm_chain::chains::conversation::Chain;
 use llm_chain::prompt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub bot: BotConfig,
     pub server: ServerConfig,
+    #[serde(default)]
+    pub ip_discovery: IpDiscoveryConfig,
+    #[serde(default)]
+    pub ip_change: IpChangeConfig,
+    #[serde(default)]
+    pub ddns: DdnsConfig,
+}
+
+/// The dns records pointed to the public ip when it changes.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct DdnsConfig {
+    #[serde(default)]
+    pub providers: Vec<DdnsProviderConfig>,
+    /// How many times a failed update is tried again.
+    pub retries: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DdnsProviderConfig {
+    /// The subdomains (e.g "mybot" for mybot.duckdns.org) of a DuckDNS account.
+    #[serde(rename = "duckdns")]
+    DuckDns {
+        domains: Vec<String>,
+        token: String,
+        base_url: Option<String>,
+    },
+    /// A record of a zone, with an API token allowed to edit its dns.
+    Cloudflare {
+        api_token: String,
+        zone_id: String,
+        record: String,
+        base_url: Option<String>,
+    },
+    /// A dynamic update (RFC 2136) sent with `nsupdate`, signed with the TSIG key file if any.
+    Nsupdate {
+        server: String,
+        zone: String,
+        record: String,
+        ttl: Option<u32>,
+        key_file: Option<PathBuf>,
+    },
+    /// A GET request, with "{ip}" in the url replaced by the address.
+    Http { url: String },
+}
+
+/// What happens when the public ip changes.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct IpChangeConfig {
+    /// The chats told about the new address, the owner chat by default.
+    pub notify_chats: Option<Vec<String>>,
+    /// Where the last addresses are kept, "ip_history.json" by default.
+    pub history_file: Option<PathBuf>,
+    /// How many addresses are kept.
+    pub history_size: Option<usize>,
+}
+
+/// How the public ip is found, the echo services of the ipify kind by default.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct IpDiscoveryConfig {
+    #[serde(default)]
+    pub policy: IpPolicy,
+    #[serde(default)]
+    pub providers: Vec<IpProviderConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpPolicy {
+    /// The answer of the first provider that succeeds, in the configured order.
+    #[default]
+    FirstSuccess,
+    /// The address given by most of the providers that succeed.
+    Majority,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum IpProviderConfig {
+    /// A service answering with the ip of the caller, in text or in json.
+    Http { url: String },
+    /// A STUN server, e.g "stun.l.google.com:19302".
+    Stun { server: String },
+    /// The NAT-PMP service of the router, e.g "192.168.1.1".
+    NatPmp { gateway: String },
+    /// The control url of the WANIPConnection service of the router.
+    Upnp { control_url: String },
+    /// The address of a local interface, when it is the public one.
+    Interface { name: String },
 }
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct BotConfig {
@@ -29,6 +119,39 @@ pub struct BotConfig {
     pub chat_id: String,
     pub db_token: String,
     pub coinmarket_token: String,
+    #[serde(default)]
+    pub mode: UpdateMode,
+    /// Replies longer than this (in characters) are sent as a text file.
+    pub document_threshold: Option<usize>,
+    /// In groups, only answer the messages mentioning the bot or replying to it.
+    #[serde(default)]
+    pub mention_only: bool,
+    /// The user ids of each role, the owner (`chat_id`) has the "owner" role.
+    #[serde(default)]
+    pub roles: HashMap<String, Vec<u64>>,
+    /// Where the owner decisions about the unknown users are kept, "approvals.json" by default.
+    pub approvals_file: Option<PathBuf>,
+    /// How many updates can be handled at the same time, across all the chats.
+    pub max_concurrent_handlers: Option<usize>,
+    /// Where the id of the last handled update is kept, to drop the ones delivered again after a restart.
+    pub update_id_file: Option<PathBuf>,
+    /// The `secret_token` of a webhook set manually, it is generated otherwise.
+    pub webhook_secret: Option<String>,
+    /// Where the generated secret is kept, so that a restart does not set the webhook again.
+    pub webhook_secret_file: Option<PathBuf>,
+    /// The Bot API server, e.g a local one, "https://api.telegram.org" by default.
+    pub api_url: Option<String>,
+}
+
+/// How the bot receives its updates.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpdateMode {
+    /// The bot provider pushes the updates to our server.
+    #[default]
+    Webhook,
+    /// We long poll the bot provider for new updates, no server is needed.
+    Polling,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -39,13 +162,102 @@ pub struct ServerConfig {
     pub pubkey_path: String,
     #[serde(alias = "privkeyfile")]
     pub privkey_path: String,
+    /// How many received updates can wait to be handled, before answering 503 to telegram.
+    pub queue_size: Option<usize>,
+    /// The networks (e.g "149.154.160.0/20") allowed to call the webhook, the ones of the bot provider by default.
+    pub allowed_ips: Option<Vec<IpNet>>,
+    /// The reverse proxies trusted to tell the client address (`X-Forwarded-For` or `Forwarded`).
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// The public address used for the webhook and the certificate.
+    #[serde(default)]
+    pub ip_family: IpFamily,
+    /// When binding an ipv6 address, refuse the ipv4 clients instead of serving both.
+    #[serde(default)]
+    pub ipv6_only: bool,
+    /// The certificate is renewed this many days before it expires.
+    pub renew_before_days: Option<u32>,
+    #[serde(default)]
+    pub certificate: CertificateConfig,
+    /// Gets a publicly trusted certificate for a domain, instead of the self-signed one.
+    pub acme: Option<AcmeConfig>,
+    /// The port of the webhook url, when it is forwarded to `port` (443 by default).
+    pub public_port: Option<u16>,
+    /// The path of the webhook url, preferably a random one.
+    pub webhook_path: Option<String>,
+}
+
+impl ServerConfig {
+    /// The path the webhook is served on, "/" by default.
+    pub fn webhook_path(&self) -> String {
+        let path = self.webhook_path.as_deref().unwrap_or_default();
+        format!("/{}", path.trim_matches('/'))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AcmeConfig {
+    /// Has to resolve to the public ip of the bot.
+    pub domain: String,
+    /// Let's Encrypt by default.
+    pub directory_url: Option<String>,
+    /// The email given to the certificate authority.
+    pub contact: Option<String>,
+    pub account_key_path: Option<PathBuf>,
+    /// Where the HTTP-01 challenge is served, 80 by default.
+    pub http_port: Option<u16>,
+    /// A root certificate to trust for the directory (e.g the one of a test CA).
+    pub ca_cert: Option<PathBuf>,
+}
+
+/// How the self-signed certificate of the webhook is generated.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CertificateConfig {
+    pub key: KeyAlgorithm,
+    /// The subject of the certificate, its common name being the ip.
+    pub country: Option<String>,
+    pub state: Option<String>,
+    pub organization: Option<String>,
+}
+
+impl Default for CertificateConfig {
+    fn default() -> Self {
+        Self {
+            key: KeyAlgorithm::default(),
+            country: None,
+            state: None,
+            organization: Some("Polybot".to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    #[default]
+    Rsa2048,
+    Rsa3072,
+    Rsa4096,
+    EcdsaP256,
+    /// Not accepted by every client.
+    Ed25519,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    #[default]
+    Ipv4,
+    Ipv6,
 }
 
 #[enum_dispatch]
 pub trait BotMessage {
     fn get_message(&self) -> String;
     fn get_user(&self) -> (u64, String);
-    fn get_chat_id(&self) -> u64;
+    /// Negative for group chats.
+    fn get_chat_id(&self) -> i64;
 }
 
 /// Here we can fill out all of the implementors of Bot and their respective
@@ -56,7 +268,9 @@ pub enum BotMessages {
 }
 
 pub type SharedUser = Arc<RwLock<BotUser>>;
-pub type SharedUsers = Arc<Mutex<HashMap<u64, SharedUser>>>;
+/// The users state is kept per (chat id, user id), so that the state of a user
+/// in a group does not leak into their private chat.
+pub type SharedUsers = Arc<Mutex<HashMap<(i64, u64), SharedUser>>>;
 pub type CommandHashMap = HashMap<String, Box<dyn BotCommandHandler + Send + Sync>>;
 
 #[async_trait]
@@ -64,16 +278,43 @@ pub trait Bot: Send + Sync + 'static {
     async fn initialize(&self) -> Result<()>;
     async fn send_message(&self, dest: &str, msg: &str) -> Result<()>;
     async fn handle_message(&self, msg: String) -> Result<()>;
-    async fn is_webhook_configured(&self, ip: &str) -> Result<bool>;
-    async fn update_webhook_cert(&self, cert: PathBuf, ip: &str) -> Result<()>;
-    fn get_webhook_ips(&self) -> Result<Vec<&'static str>>;
+    /// The chat of the update, the updates of a same chat are handled in order.
+    fn update_chat_id(&self, _msg: &str) -> Option<i64> {
+        None
+    }
+    /// Whether the webhook is set to `url` by this instance of the bot.
+    async fn is_webhook_configured(&self, url: &str) -> Result<bool>;
+    async fn get_webhook_status(&self) -> Result<WebhookStatus>;
+    /// Uploads the certificate, along with a new secret token for the webhook.
+    async fn update_webhook_cert(&self, cert: PathBuf, url: &str) -> Result<()>;
+    /// Sets the webhook of a host with a publicly trusted certificate, along with a new secret token.
+    async fn update_webhook(&self, url: &str) -> Result<()>;
+    /// The token that the webhook requests must carry, if one is set.
+    fn webhook_secret(&self) -> Option<String>;
+    async fn delete_webhook(&self) -> Result<()>;
+    /// Waits for the next batch of updates and acknowledges them, they are
+    /// not handled yet.
+    async fn poll_updates(&self) -> Result<Vec<String>>;
+    /// The networks the webhook requests come from.
+    fn get_webhook_ips(&self) -> Result<Vec<IpNet>>;
     fn new(config: BotConfig) -> Self
     where
         Self: Sized;
 }
 
+/// Delivery state of the webhook, as seen by the bot provider.
+#[derive(Debug, Clone, Default)]
+pub struct WebhookStatus {
+    pub url: String,
+    pub pending_updates: u32,
+    /// Date and description of the most recent delivery error.
+    pub last_error: Option<(DateTime<Utc>, String)>,
+}
+
 pub trait BotCommands: Default + Send + Sync {
     fn command_list() -> CommandHashMap;
+    /// Handlers of the buttons callbacks, keyed by the prefix of the callback data.
+    fn callback_list() -> CommandHashMap;
     fn chat_start_command() -> Option<&'static str>;
     fn chat_exit_command() -> Option<&'static str>;
     fn llm_request_command() -> Option<&'static str>;
@@ -81,7 +322,166 @@ pub trait BotCommands: Default + Send + Sync {
 
 #[async_trait]
 pub trait BotCommandHandler {
-    async fn handle(&self, user: SharedUser, args: String) -> String;
+    async fn handle(&self, user: SharedUser, args: String) -> BotReply;
+    /// The roles allowed to run the handler, empty if everyone is allowed.
+    fn allowed_roles(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
+
+/// Formatting of the reply text.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    MarkdownV2,
+    #[serde(rename = "HTML")]
+    Html,
+    /// Legacy markdown, prefer `MarkdownV2`.
+    Markdown,
+}
+
+/// A file to be sent, either hosted somewhere or uploaded by the bot.
+#[derive(Debug, Clone)]
+pub enum FileSource {
+    Url(String),
+    Bytes { file_name: String, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone)]
+pub enum Button {
+    /// Calls back the bot with `data`, see the `callback` macro.
+    Callback {
+        text: String,
+        data: String,
+    },
+    Url {
+        text: String,
+        url: String,
+    },
+}
+
+impl Button {
+    pub fn callback(text: impl Into<String>, data: impl Into<String>) -> Self {
+        Button::Callback {
+            text: text.into(),
+            data: data.into(),
+        }
+    }
+
+    pub fn url(text: impl Into<String>, url: impl Into<String>) -> Self {
+        Button::Url {
+            text: text.into(),
+            url: url.into(),
+        }
+    }
+}
+
+/// Buttons shown under a message, laid out in rows.
+#[derive(Debug, Clone, Default)]
+pub struct Keyboard {
+    pub rows: Vec<Vec<Button>>,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn row(mut self, buttons: Vec<Button>) -> Self {
+        self.rows.push(buttons);
+        self
+    }
+}
+
+/// The answer of a command handler.
+#[derive(Debug, Clone, Default)]
+pub enum BotReply {
+    Text {
+        text: String,
+        parse_mode: Option<ParseMode>,
+        keyboard: Option<Keyboard>,
+    },
+    /// Replaces the message whose button triggered the callback, it is sent
+    /// as a new message otherwise.
+    Edit {
+        text: String,
+        parse_mode: Option<ParseMode>,
+        keyboard: Option<Keyboard>,
+    },
+    Photo {
+        photo: FileSource,
+        caption: Option<String>,
+    },
+    Document {
+        document: FileSource,
+        caption: Option<String>,
+    },
+    Location {
+        latitude: f64,
+        longitude: f64,
+    },
+    /// Sent in order, as separate messages.
+    Multiple(Vec<BotReply>),
+    /// The handler has nothing to answer.
+    #[default]
+    NoReply,
+}
+
+impl BotReply {
+    pub fn text(text: impl Into<String>) -> Self {
+        BotReply::Text {
+            text: text.into(),
+            parse_mode: None,
+            keyboard: None,
+        }
+    }
+
+    pub fn formatted(text: impl Into<String>, parse_mode: ParseMode) -> Self {
+        BotReply::Text {
+            text: text.into(),
+            parse_mode: Some(parse_mode),
+            keyboard: None,
+        }
+    }
+
+    pub fn edit(text: impl Into<String>) -> Self {
+        BotReply::Edit {
+            text: text.into(),
+            parse_mode: None,
+            keyboard: None,
+        }
+    }
+
+    /// Attaches the buttons to a text (or edit) reply, other replies are unchanged.
+    pub fn with_keyboard(mut self, buttons: Keyboard) -> Self {
+        if let BotReply::Text { keyboard, .. } | BotReply::Edit { keyboard, .. } = &mut self {
+            *keyboard = Some(buttons);
+        }
+        self
+    }
+
+    /// Flattens the nested `Multiple` replies into the list of messages to send.
+    pub fn into_messages(self) -> Vec<BotReply> {
+        match self {
+            BotReply::Multiple(replies) => replies
+                .into_iter()
+                .flat_map(BotReply::into_messages)
+                .collect(),
+            BotReply::NoReply => vec![],
+            reply => vec![reply],
+        }
+    }
+}
+
+impl From<String> for BotReply {
+    fn from(text: String) -> Self {
+        BotReply::text(text)
+    }
+}
+
+impl From<&str> for BotReply {
+    fn from(text: &str) -> Self {
+        BotReply::text(text)
+    }
 }
 
 #[derive(Default)]
@@ -153,3 +553,35 @@ pub trait WeatherProvider: Sync + Send + Clone {
     async fn get_temp_forecast(&self, city: String, time: ForecastTime) -> Option<f32>;
     fn get_favourite_city(&self) -> String;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reply_messages() {
+        let reply = BotReply::Multiple(vec![
+            "first".into(),
+            BotReply::NoReply,
+            BotReply::Multiple(vec![
+                BotReply::edit("second").with_keyboard(Keyboard::default()),
+                BotReply::Location {
+                    latitude: 48.8,
+                    longitude: 2.3,
+                },
+            ]),
+        ]);
+        let messages: Vec<String> = reply
+            .into_messages()
+            .into_iter()
+            .map(|message| match message {
+                BotReply::Text { text, keyboard, .. } => format!("{text} {}", keyboard.is_some()),
+                BotReply::Edit { text, keyboard, .. } => format!("{text} {}", keyboard.is_some()),
+                BotReply::Location { .. } => "location".to_string(),
+                message => panic!("Unexpected message {message:?}"),
+            })
+            .collect();
+        assert_eq!(messages, ["first false", "second true", "location"]);
+        assert!(BotReply::NoReply.into_messages().is_empty());
+    }
+}
@@ -1,16 +1,26 @@
+use crate::types::{CertificateConfig, KeyAlgorithm};
 use crate::Config;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 
+use chrono::{DateTime, Utc};
 use openssl::{
     asn1::Asn1Time,
     bn::{BigNum, MsbOption},
-    pkey::PKey,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
     rsa::Rsa,
-    x509::{X509NameBuilder, X509},
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
 };
 use reqwest::header::CONTENT_TYPE;
 use serde::Deserialize;
-use std::{env, path::PathBuf};
+use std::{
+    env,
+    net::Ipv6Addr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs::{self, File},
     io::AsyncWriteExt,
@@ -27,36 +37,89 @@ pub async fn get_config(file: &str) -> Result<Config> {
     Ok(map)
 }
 
-#[derive(Deserialize)]
-struct Ipify {
-    ip: String,
+/// The ports allowed by telegram for the webhooks.
+pub const WEBHOOK_PORTS: [u16; 4] = [443, 80, 88, 8443];
+
+/// The url of the webhook, `host` being a domain or an ip.
+pub fn webhook_url(host: &str, port: Option<u16>, path: &str) -> Result<String> {
+    let host = url_host(host);
+    match port.unwrap_or(443) {
+        443 => Ok(format!("https://{host}{path}")),
+        port if WEBHOOK_PORTS.contains(&port) => Ok(format!("https://{host}:{port}{path}")),
+        port => {
+            bail!("The port {port} is not allowed for the webhook, use one of {WEBHOOK_PORTS:?}")
+        }
+    }
 }
-pub async fn get_ip() -> Result<String> {
-    let resp: String = reqwest::Client::new()
-        .get("https://api.ipify.org?format=json")
-        .header(CONTENT_TYPE, "application/json")
-        .send()
-        .await?
-        .text()
+
+/// The ip as written in urls, i.e with brackets for ipv6 literals.
+pub fn url_host(ip: &str) -> String {
+    match ip.parse::<Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]"),
+        Err(_) => ip.to_string(),
+    }
+}
+
+pub fn generate_key(algorithm: KeyAlgorithm) -> Result<PKey<Private>> {
+    let key = match algorithm {
+        KeyAlgorithm::Rsa2048 => PKey::from_rsa(Rsa::generate(2048)?)?,
+        KeyAlgorithm::Rsa3072 => PKey::from_rsa(Rsa::generate(3072)?)?,
+        KeyAlgorithm::Rsa4096 => PKey::from_rsa(Rsa::generate(4096)?)?,
+        KeyAlgorithm::EcdsaP256 => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+            PKey::from_ec_key(EcKey::generate(&group)?)?
+        }
+        KeyAlgorithm::Ed25519 => PKey::generate_ed25519()?,
+    };
+    Ok(key)
+}
+
+/// Writes the key in PEM, only readable by its owner.
+pub async fn write_private_key(path: &Path, key: &PKey<Private>) -> Result<()> {
+    write_secret(path, &key.private_key_to_pem_pkcs8()?).await
+}
+
+/// Writes a file only readable by its owner.
+pub async fn write_secret(path: &Path, content: &[u8]) -> Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .await
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    // the mode only applies to new files.
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))
         .await?;
-    let result: Ipify = serde_json::from_str(&resp).context("Failed to get the ip address")?;
-    Ok(result.ip)
+    file.write_all(content).await?;
+    file.flush().await?;
+    file.sync_all().await?;
+    Ok(())
 }
 
+/// Generates a self-signed certificate for `ip`, the private key being only
+/// readable by its owner.
 pub async fn generate_certificate(
     pubkey: PathBuf,
     privkey: PathBuf,
     ip: &str,
-    name: &str,
+    settings: &CertificateConfig,
 ) -> Result<()> {
-    let rsa = Rsa::generate(2048)?;
-    let key_pair = PKey::from_rsa(rsa)?;
+    let key_pair = generate_key(settings.key)?;
 
     let mut x509_name = X509NameBuilder::new()?;
-    x509_name.append_entry_by_text("C", "DE")?;
-    x509_name.append_entry_by_text("ST", "B")?;
-    x509_name.append_entry_by_text("O", name)?;
-    x509_name.append_entry_by_text("CN", ip)?;
+    let subject = [
+        ("C", &settings.country),
+        ("ST", &settings.state),
+        ("O", &settings.organization),
+    ];
+    for (field, value) in subject {
+        if let Some(value) = value {
+            x509_name.append_entry_by_text(field, value)?;
+        }
+    }
+    x509_name.append_entry_by_text("CN", &url_host(ip))?;
     let x509_name = x509_name.build();
 
     let mut cert_builder = X509::builder()?;
@@ -77,24 +140,43 @@ pub async fn generate_certificate(
     let not_after = Asn1Time::days_from_now(365)?;
     cert_builder.set_not_after(&not_after)?;
 
-    cert_builder.sign(&key_pair, openssl::hash::MessageDigest::sha256())?;
+    // the clients check the address against the SAN, not the CN.
+    let san = SubjectAlternativeName::new()
+        .ip(ip)
+        .build(&cert_builder.x509v3_context(None, None))?;
+    cert_builder.append_extension(san)?;
+
+    // ed25519 signs the whole message, without a separate digest.
+    let digest = match settings.key {
+        KeyAlgorithm::Ed25519 => MessageDigest::null(),
+        _ => MessageDigest::sha256(),
+    };
+    cert_builder.sign(&key_pair, digest)?;
     let cert = cert_builder.build();
 
     fs::write(&pubkey, cert.to_pem()?).await?;
-    fs::write(&privkey, &key_pair.private_key_to_pem_pkcs8()?).await?;
+    write_private_key(&privkey, &key_pair).await?;
 
     let mut file = File::open(&pubkey).await?;
     file.flush().await?;
     file.sync_all().await?;
 
-    let mut file = File::open(&privkey).await?;
-    file.flush().await?;
-    file.sync_all().await?;
-
     info!("Generated the keys !");
     Ok(())
 }
 
+/// The end of validity of the first certificate of the PEM file.
+pub fn certificate_expiry(pubkey: &Path) -> Result<DateTime<Utc>> {
+    let pem =
+        std::fs::read(pubkey).with_context(|| format!("Could not read {}", pubkey.display()))?;
+    let cert = X509::from_pem(&pem)
+        .with_context(|| format!("Invalid certificate {}", pubkey.display()))?;
+    let left = Asn1Time::days_from_now(0)?.diff(cert.not_after())?;
+    Ok(Utc::now()
+        + chrono::Duration::days(left.days.into())
+        + chrono::Duration::seconds(left.secs.into()))
+}
+
 #[derive(Deserialize)]
 pub struct Affirmation {
     pub affirmation: String,
@@ -113,6 +195,153 @@ pub async fn get_affirmation() -> Result<String> {
     let text: Affirmation = serde_json::from_str(&resp).unwrap();
     Ok(text.affirmation)
 }
+
+/// Splits `text` in chunks of at most `limit` characters, cutting preferably between
+/// paragraphs, then sentences, then words. Code blocks are kept whole when possible,
+/// otherwise they are cut between lines with their fences repeated in each chunk.
+pub fn split_text(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = vec![];
+    let mut current = String::new();
+    for block in text_blocks(text) {
+        // rather start a new chunk than cutting the block, if it fits in one.
+        if !current.is_empty() && char_len(&current) + char_len(&block) > limit {
+            chunks.push(current.trim().to_string());
+            current.clear();
+        }
+        let parts = if char_len(&block) <= limit {
+            vec![block]
+        } else if block.trim_start().starts_with("```") {
+            split_code_block(&block, limit)
+        } else {
+            split_prose(&block, limit)
+        };
+        for part in parts {
+            if !current.is_empty() && char_len(&current) + char_len(&part) > limit {
+                chunks.push(current.trim().to_string());
+                current.clear();
+            }
+            current.push_str(&part);
+        }
+    }
+    chunks.push(current.trim().to_string());
+    chunks.retain(|chunk| !chunk.is_empty());
+    chunks
+}
+
+fn char_len(text: &str) -> usize {
+    text.chars().count()
+}
+
+/// Cuts the text in paragraphs and code blocks, keeping their separators.
+fn text_blocks(text: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut current = String::new();
+    let mut in_code = false;
+    for line in text.split_inclusive('\n') {
+        let is_fence = line.trim_start().starts_with("```");
+        if is_fence && !in_code && !current.is_empty() {
+            blocks.push(std::mem::take(&mut current));
+        }
+        current.push_str(line);
+        if is_fence {
+            in_code = !in_code;
+            if !in_code {
+                blocks.push(std::mem::take(&mut current));
+            }
+        } else if !in_code && line.trim().is_empty() {
+            blocks.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        blocks.push(current);
+    }
+    blocks
+}
+
+/// Cuts the text right after each one of the separators.
+fn split_after<'a>(text: &'a str, separators: &[&str]) -> Vec<&'a str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    for (i, _) in text.char_indices() {
+        if i < start {
+            continue;
+        }
+        if let Some(sep) = separators.iter().find(|sep| text[i..].starts_with(**sep)) {
+            parts.push(&text[start..i + sep.len()]);
+            start = i + sep.len();
+        }
+    }
+    if start < text.len() {
+        parts.push(&text[start..]);
+    }
+    parts
+}
+
+fn split_chars(text: &str, limit: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    chars.chunks(limit.max(1)).map(String::from_iter).collect()
+}
+
+fn split_prose(text: &str, limit: usize) -> Vec<String> {
+    let mut parts = vec![];
+    for sentence in split_after(text, &[". ", "! ", "? ", "\n"]) {
+        if char_len(sentence) <= limit {
+            parts.push(sentence.to_string());
+            continue;
+        }
+        for word in split_after(sentence, &[" "]) {
+            if char_len(word) <= limit {
+                parts.push(word.to_string());
+            } else {
+                parts.extend(split_chars(word, limit));
+            }
+        }
+    }
+    parts
+}
+
+fn split_code_block(block: &str, limit: usize) -> Vec<String> {
+    let mut lines = block.split_inclusive('\n');
+    let opening = lines.next().unwrap_or_default();
+    let opening = if opening.ends_with('\n') {
+        opening.to_string()
+    } else {
+        format!("{opening}\n")
+    };
+    const CLOSING: &str = "```\n";
+    let body_limit = limit.saturating_sub(char_len(&opening) + CLOSING.len());
+    if body_limit == 0 {
+        return split_chars(block, limit);
+    }
+
+    let mut parts = vec![];
+    let mut body = String::new();
+    for line in lines {
+        if line.trim_start().starts_with("```") {
+            break;
+        }
+        let pieces = if char_len(line) <= body_limit {
+            vec![line.to_string()]
+        } else {
+            split_chars(line, body_limit)
+        };
+        for piece in pieces {
+            if !body.is_empty() && char_len(&body) + char_len(&piece) > body_limit {
+                parts.push(format!("{opening}{body}{CLOSING}"));
+                body.clear();
+            }
+            body.push_str(&piece);
+        }
+    }
+    if !body.is_empty() {
+        if !body.ends_with('\n') {
+            body.push('\n');
+        }
+        parts.push(format!("{opening}{body}{CLOSING}"));
+    }
+    parts
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -163,7 +392,7 @@ mod tests {
         });
 
         let resp = reqwest::Client::new()
-            .get(&server.url("/"))
+            .get(server.url("/"))
             .header(CONTENT_TYPE, "application/json")
             .send()
             .await
@@ -177,4 +406,131 @@ mod tests {
         mock.assert();
         assert_eq!(text.affirmation, "You are awesome!");
     }
+
+    #[tokio::test]
+    async fn test_certificate_expiry() {
+        let dir = tempdir().unwrap();
+        let pubkey = dir.path().join("cert.pem");
+        generate_certificate(
+            pubkey.clone(),
+            dir.path().join("key.pem"),
+            "1.1.1.1",
+            &CertificateConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        let left = certificate_expiry(&pubkey).unwrap() - Utc::now();
+        assert!((364..=365).contains(&left.num_days()));
+        assert!(certificate_expiry(&dir.path().join("missing.pem")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_generate_certificate() {
+        let dir = tempdir().unwrap();
+        let pubkey = dir.path().join("cert.pem");
+        let privkey = dir.path().join("key.pem");
+        // an existing key keeps its permissions otherwise.
+        std::fs::write(&privkey, "").unwrap();
+        std::fs::set_permissions(&privkey, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        let algorithms = [
+            (KeyAlgorithm::Rsa3072, openssl::pkey::Id::RSA),
+            (KeyAlgorithm::EcdsaP256, openssl::pkey::Id::EC),
+            (KeyAlgorithm::Ed25519, openssl::pkey::Id::ED25519),
+        ];
+        for (algorithm, id) in algorithms {
+            let settings = CertificateConfig {
+                key: algorithm,
+                country: Some("DE".to_string()),
+                ..Default::default()
+            };
+            generate_certificate(pubkey.clone(), privkey.clone(), "2001:db8::1", &settings)
+                .await
+                .unwrap();
+
+            let cert = X509::from_pem(&std::fs::read(&pubkey).unwrap()).unwrap();
+            let key = PKey::private_key_from_pem(&std::fs::read(&privkey).unwrap()).unwrap();
+            assert_eq!(key.id(), id);
+            assert!(cert.public_key().unwrap().public_eq(&key));
+            let san = cert.subject_alt_names().unwrap();
+            assert_eq!(
+                san.iter().next().unwrap().ipaddress().unwrap(),
+                "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets()
+            );
+            let subject: Vec<_> = cert
+                .subject_name()
+                .entries()
+                .map(|entry| entry.data().as_utf8().unwrap().to_string())
+                .collect();
+            assert_eq!(subject, vec!["DE", "Polybot", "[2001:db8::1]"]);
+            let mode = std::fs::metadata(&privkey).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn test_webhook_url() {
+        assert_eq!(
+            webhook_url("11.22.33.44", None, "/").unwrap(),
+            "https://11.22.33.44/"
+        );
+        assert_eq!(
+            webhook_url("2001:db8::1", Some(8443), "/a1b2c3").unwrap(),
+            "https://[2001:db8::1]:8443/a1b2c3"
+        );
+        assert_eq!(
+            webhook_url("bot.example.com", Some(443), "/hook").unwrap(),
+            "https://bot.example.com/hook"
+        );
+        assert!(webhook_url("11.22.33.44", Some(4443), "/").is_err());
+    }
+
+    #[test]
+    fn test_url_host() {
+        assert_eq!(url_host("11.22.33.44"), "11.22.33.44");
+        assert_eq!(url_host("2001:db8::1"), "[2001:db8::1]");
+        assert_eq!(url_host("2001:0db8:0:0:0:0:0:1"), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn test_split_short_text() {
+        assert_eq!(split_text("Hello there!", 20), vec!["Hello there!"]);
+    }
+
+    #[test]
+    fn test_split_on_paragraphs_and_sentences() {
+        let text = "First paragraph.\n\nSecond one. It has two sentences.";
+        assert_eq!(
+            split_text(text, 30),
+            vec!["First paragraph.", "Second one.", "It has two sentences."]
+        );
+        assert_eq!(
+            split_text(text, 50),
+            vec!["First paragraph.", "Second one. It has two sentences."]
+        );
+        assert_eq!(split_text(text, 60), vec![text]);
+    }
+
+    #[test]
+    fn test_split_long_words() {
+        let chunks = split_text(&"a".repeat(25), 10);
+        assert_eq!(chunks, vec!["a".repeat(10), "a".repeat(10), "a".repeat(5)]);
+    }
+
+    #[test]
+    fn test_split_code_block() {
+        let text = "Look:\n```rust\nlet a = 1;\nlet b = 2;\n```\nDone.";
+        assert_eq!(
+            split_text(text, 30),
+            vec![
+                "Look:",
+                "```rust\nlet a = 1;\n```",
+                "```rust\nlet b = 2;\n```\nDone."
+            ]
+        );
+        for chunk in split_text(text, 30) {
+            assert!(chunk.chars().count() <= 30);
+        }
+    }
 }
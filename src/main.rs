@@ -1,6 +1,7 @@
 mod bot_commands;
 use anyhow::Result;
 use bot_commands::commands::MyCommands;
+use polybot::plant::PlantServer;
 use polybot::polybot::Polybot;
 use polybot::telegram::bot::TelegramBot;
 use std::error::Error;
@@ -19,8 +20,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .init();
 
     let config = polybot::utils::get_config("config.toml").await?;
-    let telegrambot =
-        Polybot::<MyBot>::new(config).with_webhook_monitoring(Duration::from_secs(60));
+    let plant = PlantServer::new(
+        "192.168.2.214",
+        &config.bot.chat_id,
+        3333,
+        &config.bot.db_token,
+    );
+    let telegrambot = Polybot::<MyBot>::new(config)
+        .with_webhook_monitoring(Duration::from_secs(60))
+        .with_service(plant);
+    bot_commands::SERVICES.set(telegrambot.services()).ok();
+    bot_commands::UPDATES.set(telegrambot.queue_stats()).ok();
 
     info!("Starting Telegram Bot ...");
     telegrambot.start_loop().await?;